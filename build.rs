@@ -9,9 +9,24 @@ use std::{
 mod storage;
 
 use prost::Message;
-use storage::{document::DocumentMap, trie::Trie, word::WordMap};
+use storage::{
+    completion::QueryLog,
+    document::{Analyzer, AttributeValues, Attributes},
+    index::IndexBuilder,
+    trie::Trie,
+};
+
+/// Facet fields of the index schema, overridable with the `FACETS`
+/// environment variable. `year:10` counts years by decade.
+const DEFAULT_FACETS: &str = "genre,language,year:10";
 
 fn main() {
+    // protoc comes with the build dependencies unless one is configured.
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    }
+    tonic_build::compile_protos("src/proto/search.proto").unwrap();
+
     let mut config = prost_build::Config::new();
     config.btree_map(&["."]);
 
@@ -25,6 +40,8 @@ fn main() {
     let trie_fname = "trie.bin";
     let word_map_fname = "word_map.proto.bin";
     let doc_map_fname = "doc_map.proto.bin";
+    let query_log_fname = "query_log.proto.bin";
+    let successors_fname = "successors.proto.bin";
 
     let trie_path = Path::new(trie_fname);
     let word_map_path = Path::new(word_map_fname);
@@ -34,8 +51,7 @@ fn main() {
     //     return;
     // }
 
-    let mut word_map = WordMap::new();
-    let mut doc_map = DocumentMap::new();
+    let mut builder = IndexBuilder::new(Analyzer::Standard);
 
     let file = File::open("./movie_title_tmdb.txt").unwrap();
 
@@ -49,8 +65,28 @@ fn main() {
             // if line.len() > 32 {
             //     line = line[..32].to_owned();
             // }
-            let d = doc_map.add_doc(line.to_owned());
-            d.process(&mut word_map);
+            // Each line is a title, optionally followed by tab separated
+            // columns: a boost (a popularity signal such as a vote count)
+            // and `name=value` attributes, e.g. `year=1995` or
+            // `genre=Action,Crime` for several values.
+            let mut columns = line.split('\t');
+            let title = columns.next().unwrap_or_default();
+            let boost = columns
+                .next()
+                .and_then(|boost| boost.trim().parse().ok())
+                .unwrap_or(0);
+            let mut attributes = Attributes::default();
+            for column in columns {
+                if let Some((name, values)) = column.split_once('=') {
+                    attributes.fields.insert(
+                        name.trim().to_owned(),
+                        AttributeValues {
+                            values: values.split(',').map(|v| v.trim().to_owned()).collect(),
+                        },
+                    );
+                }
+            }
+            builder.add_doc(title, boost, attributes);
             // mx_len = max(mx_len, line.len());
             println!("{idx}\r");
         } else {
@@ -62,6 +98,9 @@ fn main() {
         // }
     }
 
+    let (word_map, doc_map, successors) =
+        builder.build(&std::env::var("FACETS").unwrap_or(DEFAULT_FACETS.to_string()));
+
     let mut words = vec![];
     for (w, _) in word_map.word_hash.iter() {
         words.push(w);
@@ -86,4 +125,24 @@ fn main() {
     let buf = Message::encode_to_vec(&word_map);
     file.write_all(&buf).expect("Failed to write doc_map");
     // bincode::serialize_into(file, &word_map).expect("Failed to serialize word_map");
+
+    let mut file = File::create(successors_fname).expect("Failed to create data file");
+    let buf = Message::encode_to_vec(&successors);
+    file.write_all(&buf).expect("Failed to write successors");
+
+    // Past queries for completion, one `query` or `query<TAB>count` per line.
+    if let Ok(file) = File::open("./query_log.txt") {
+        let mut query_log = QueryLog::new();
+        for line in BufReader::new(file).lines().flatten() {
+            let (query, count) = match line.split_once('\t') {
+                Some((query, count)) => (query, count.trim().parse().unwrap_or(1)),
+                None => (line.as_str(), 1),
+            };
+            query_log.add(query, count);
+        }
+
+        let mut file = File::create(query_log_fname).expect("Failed to create data file");
+        let buf = Message::encode_to_vec(&query_log);
+        file.write_all(&buf).expect("Failed to write query_log");
+    }
 }
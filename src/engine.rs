@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::{fmt, path::Path, path::PathBuf};
+
+use prost::Message;
+use serde::Deserialize;
+
+use crate::search::{search_index, CandidateCache, Reuse, SearchSession};
+use crate::storage::document::{AttributeValues, Attributes, DocumentMap};
+use crate::storage::highlight::{TagAllowlist, DEFAULT_ALLOWED_TAGS};
+use crate::storage::index::{
+    Index, IndexBuilder, IndexSettings, DOC_MAP_FNAME, SETTINGS_FNAME, SUCCESSORS_FNAME,
+    WORD_MAP_FNAME,
+};
+use crate::storage::successor::SuccessorMap;
+use crate::storage::word::WordMap;
+use crate::{SearchRequest, SearchResponse};
+
+/// Loads the index stored in `dir`, see `Index::save`.
+fn load_index(dir: &Path) -> Result<Index, Error> {
+    let word_map: WordMap = load_message(dir.join(WORD_MAP_FNAME))?
+        .ok_or_else(|| missing_file(dir, WORD_MAP_FNAME))?;
+    let doc_map: DocumentMap = load_message(dir.join(DOC_MAP_FNAME))?
+        .ok_or_else(|| missing_file(dir, DOC_MAP_FNAME))?;
+    let settings = load_message(dir.join(SETTINGS_FNAME))?.unwrap_or_default();
+    let successors = load_message(dir.join(SUCCESSORS_FNAME))?.unwrap_or_else(SuccessorMap::new);
+
+    Ok(Index::new(settings, word_map, doc_map, successors))
+}
+
+fn missing_file(dir: &Path, fname: &str) -> Error {
+    Error::Internal(format!("no index in {dir:?}, {fname} is missing"))
+}
+
+/// Decodes an optional index file, `None` when it does not exist.
+pub fn load_message<M: Message + Default>(fname: impl AsRef<Path>) -> Result<Option<M>, Error> {
+    let fname = fname.as_ref();
+    let buf = match std::fs::read(fname) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Internal(format!("failed to read {fname:?}: {e}"))),
+    };
+    let buf = prost::bytes::Bytes::from(buf);
+    Message::decode(buf)
+        .map(Some)
+        .map_err(|e| Error::Internal(format!("failed to decode {fname:?}: {e}")))
+}
+
+/// Why a request to the engine failed, with a message for the caller.
+#[derive(Debug)]
+pub enum Error {
+    BadRequest(String),
+    NotFound(String),
+    /// An index of that name exists already.
+    Conflict(String),
+    /// Loading, storing or removing an index failed, the message names the
+    /// cause.
+    Internal(String),
+    /// The search was stopped by `SearchSession::cancel`.
+    Cancelled(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(message)
+            | Error::NotFound(message)
+            | Error::Conflict(message)
+            | Error::Internal(message)
+            | Error::Cancelled(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Deserialize)]
+pub struct NewDocument {
+    pub text: String,
+    pub boost: Option<u32>,
+    /// Attribute values by name, e.g. `{"genre": ["Drama", "Crime"]}`.
+    #[serde(default)]
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+impl NewDocument {
+    fn add_to(self, builder: &mut IndexBuilder) -> u32 {
+        let boost = self.boost.unwrap_or(0);
+        builder.add_doc(&self.text, boost, self.attributes())
+    }
+
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            fields: self
+                .attributes
+                .iter()
+                .map(|(name, values)| {
+                    let values = values.clone();
+                    (name.to_owned(), AttributeValues { values })
+                })
+                .collect(),
+        }
+    }
+}
+
+fn is_valid_index_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+struct StoredIndex {
+    index: Arc<Index>,
+    /// Where changes are written, `None` for indexes kept in memory.
+    dir: Option<PathBuf>,
+}
+
+/// Indexes by name and everything to search them. Calls block, async
+/// servers run them with `spawn_blocking`.
+pub struct SearchEngine {
+    indexes: RwLock<HashMap<String, StoredIndex>>,
+    indexes_dir: Option<PathBuf>,
+    highlight_tags: TagAllowlist,
+    /// Serializes changes to indexes.
+    writes: Mutex<()>,
+}
+
+enum IndexSource {
+    /// Stored in `dir`, or still only in `seed` before the first change.
+    Dir { dir: PathBuf, seed: Option<PathBuf> },
+    Memory(Arc<Index>),
+}
+
+#[derive(Default)]
+pub struct SearchEngineBuilder {
+    indexes: Vec<(String, IndexSource)>,
+    indexes_dir: Option<PathBuf>,
+    highlight_tags: Option<String>,
+}
+
+impl SearchEngineBuilder {
+    /// Serves the index stored in `dir` as `name`. Changes to its documents
+    /// are written back to `dir`.
+    pub fn load_index(mut self, name: &str, dir: impl Into<PathBuf>) -> Self {
+        let source = IndexSource::Dir {
+            dir: dir.into(),
+            seed: None,
+        };
+        self.indexes.push((name.to_owned(), source));
+        self
+    }
+
+    /// Like `load_index`, but serves the index stored in `seed` as long as
+    /// `dir` holds none. Changes are written to `dir`, so `seed`, such as
+    /// the files written by the build, is never modified.
+    pub fn seed_index(
+        mut self,
+        name: &str,
+        dir: impl Into<PathBuf>,
+        seed: impl Into<PathBuf>,
+    ) -> Self {
+        let source = IndexSource::Dir {
+            dir: dir.into(),
+            seed: Some(seed.into()),
+        };
+        self.indexes.push((name.to_owned(), source));
+        self
+    }
+
+    /// Serves an index built with `IndexBuilder` as `name`, in memory only.
+    pub fn add_index(mut self, name: &str, index: Index) -> Self {
+        self.indexes.push((name.to_owned(), IndexSource::Memory(Arc::new(index))));
+        self
+    }
+
+    /// Stores the indexes created by `SearchEngine::create_index` in
+    /// `dir/<name>`, and loads the ones already there unless an index of
+    /// that name is loaded on its own.
+    pub fn indexes_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.indexes_dir = Some(dir.into());
+        self
+    }
+
+    /// Comma separated tags allowed as highlight tags, see `TagAllowlist`.
+    pub fn highlight_tags(mut self, tags: &str) -> Self {
+        self.highlight_tags = Some(tags.to_owned());
+        self
+    }
+
+    /// Loads the indexes, fails on the first one that can't be loaded.
+    pub fn build(self) -> Result<SearchEngine, Error> {
+        let mut indexes = HashMap::new();
+        for (name, source) in self.indexes {
+            let stored = match source {
+                IndexSource::Dir { dir, seed } => {
+                    let from = match seed {
+                        Some(seed) if !dir.join(DOC_MAP_FNAME).exists() => seed,
+                        _ => dir.clone(),
+                    };
+                    StoredIndex {
+                        index: Arc::new(load_index(&from)?),
+                        dir: Some(dir),
+                    }
+                }
+                IndexSource::Memory(index) => StoredIndex {
+                    index,
+                    dir: None,
+                },
+            };
+            indexes.insert(name, stored);
+        }
+        if let Some(Ok(entries)) = self.indexes_dir.as_ref().map(std::fs::read_dir) {
+            for entry in entries.flatten().filter(|e| e.path().is_dir()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if indexes.contains_key(&name) {
+                    continue;
+                }
+                let stored = StoredIndex {
+                    index: Arc::new(load_index(&entry.path())?),
+                    dir: Some(entry.path()),
+                };
+                indexes.insert(name, stored);
+            }
+        }
+
+        Ok(SearchEngine {
+            indexes: RwLock::new(indexes),
+            indexes_dir: self.indexes_dir,
+            highlight_tags: TagAllowlist::new(
+                self.highlight_tags.as_deref().unwrap_or(DEFAULT_ALLOWED_TAGS),
+            ),
+            writes: Mutex::new(()),
+        })
+    }
+}
+
+impl SearchEngine {
+    pub fn builder() -> SearchEngineBuilder {
+        SearchEngineBuilder::default()
+    }
+
+    pub fn index(&self, name: &str) -> Result<Arc<Index>, Error> {
+        self.indexes
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|stored| stored.index.clone())
+            .ok_or_else(|| Error::NotFound(format!("no index named `{name}`")))
+    }
+
+    /// Every index, by name.
+    pub fn indexes(&self) -> Vec<(String, Arc<Index>)> {
+        let mut indexes = self
+            .indexes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, stored)| (name.to_owned(), stored.index.clone()))
+            .collect::<Vec<(String, Arc<Index>)>>();
+        indexes.sort_by(|a, b| a.0.cmp(&b.0));
+        indexes
+    }
+
+    pub fn search(&self, index: &str, request: SearchRequest) -> Result<SearchResponse, Error> {
+        let index = self.index(index)?;
+        self.search_in(&index, request)
+    }
+
+    /// Searches an index obtained from `index`, which may have been
+    /// replaced or removed since.
+    pub fn search_in(&self, index: &Index, request: SearchRequest) -> Result<SearchResponse, Error> {
+        search_index(index, &self.highlight_tags, request, Reuse::Nothing)
+    }
+
+    /// Searches the index named `index` as the latest search of `session`.
+    /// Calling `SearchSession::cancel` while it runs makes it fail with
+    /// `Error::Cancelled`, so a newer search of the session can start. It
+    /// fails the same way when the session was cancelled and not resumed
+    /// before.
+    pub fn session_search(
+        &self,
+        session: &SearchSession,
+        index: &str,
+        request: SearchRequest,
+    ) -> Result<SearchResponse, Error> {
+        let index = self.index(index)?;
+        search_index(&index, &self.highlight_tags, request, Reuse::Session(session))
+    }
+
+    /// Runs `(index name, search)` pairs on at most one thread per core.
+    /// Searches fail on their own, a panicking one as `Error::Internal`, and
+    /// searches with the same query share their candidate set.
+    pub fn multi_search(
+        &self,
+        searches: Vec<(String, SearchRequest)>,
+    ) -> Vec<Result<SearchResponse, Error>> {
+        let failed = || Err(Error::Internal("search failed".to_string()));
+        let mut results = searches.iter().map(|_| failed()).collect::<Vec<_>>();
+        let workers = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(searches.len());
+        let queue = Mutex::new(searches.into_iter().enumerate());
+        let candidates = CandidateCache::default();
+
+        thread::scope(|scope| {
+            let workers = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = vec![];
+                        loop {
+                            let Some((i, (name, search))) = queue.lock().unwrap().next() else {
+                                break done;
+                            };
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                let index = self.index(&name)?;
+                                let reuse = Reuse::Batch(&candidates);
+                                search_index(&index, &self.highlight_tags, search, reuse)
+                            }));
+                            done.push((i, result.unwrap_or_else(|_| failed())));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for worker in workers {
+                for (i, result) in worker.join().unwrap_or_default() {
+                    results[i] = result;
+                }
+            }
+        });
+        results
+    }
+
+    /// Builds an index from `documents` and stores it under the indexes
+    /// directory, if there is one.
+    pub fn create_index(
+        &self,
+        name: &str,
+        settings: IndexSettings,
+        documents: Vec<NewDocument>,
+    ) -> Result<Arc<Index>, Error> {
+        if !is_valid_index_name(name) {
+            return Err(Error::BadRequest(
+                "index names are 1 to 64 letters, digits, `-` or `_`".to_string(),
+            ));
+        }
+        settings
+            .validate()
+            .map_err(|e| Error::BadRequest(format!("invalid settings: {e}")))?;
+
+        let _guard = self.writes.lock().unwrap();
+        if self.indexes.read().unwrap().contains_key(name) {
+            return Err(Error::Conflict(format!("index `{name}` already exists")));
+        }
+
+        let mut builder = IndexBuilder::new(settings.analyzer());
+        for doc in documents {
+            doc.add_to(&mut builder);
+        }
+        let (word_map, doc_map, successors) = builder.build(&settings.facets);
+        let index = Arc::new(Index::new(settings, word_map, doc_map, successors));
+
+        let dir = self.indexes_dir.as_ref().map(|dir| dir.join(name));
+        if let Some(dir) = &dir {
+            store(&index, dir)?;
+        }
+        let stored = StoredIndex {
+            index: index.clone(),
+            dir,
+        };
+        self.indexes.write().unwrap().insert(name.to_owned(), stored);
+
+        Ok(index)
+    }
+
+    /// Removes an index and the directory it is stored in, if it was
+    /// created in the indexes directory.
+    pub fn delete_index(&self, name: &str) -> Result<(), Error> {
+        let _guard = self.writes.lock().unwrap();
+        self.index(name)?;
+
+        if let Some(dir) = self.indexes_dir.as_ref().map(|dir| dir.join(name)) {
+            match std::fs::remove_dir_all(&dir) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(Error::Internal(format!("failed to remove {dir:?}: {e}")));
+                }
+            }
+        }
+        self.indexes.write().unwrap().remove(name);
+
+        Ok(())
+    }
+
+    /// Adds documents to an index, returns their ids.
+    ///
+    /// Like every change to the documents of an index, this rebuilds the
+    /// index, which costs about as much as building it from scratch and
+    /// holds off other changes meanwhile. Changes should come in batches,
+    /// not a call per document.
+    pub fn add_documents(&self, name: &str, documents: Vec<NewDocument>) -> Result<Vec<u32>, Error> {
+        self.edit_index(name, |builder| {
+            Ok(documents.into_iter().map(|doc| doc.add_to(builder)).collect())
+        })
+    }
+
+    /// Replaces documents by id, they keep their ids. Nothing is replaced
+    /// when one of them doesn't exist. Rebuilds the index, see
+    /// `add_documents`.
+    pub fn replace_documents(
+        &self,
+        name: &str,
+        documents: Vec<(u32, NewDocument)>,
+    ) -> Result<(), Error> {
+        self.edit_index(name, |builder| {
+            for (doc_id, document) in documents {
+                let boost = document.boost.unwrap_or(0);
+                if !builder.replace_doc(doc_id, &document.text, boost, document.attributes()) {
+                    return Err(no_document(name, doc_id));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes documents by id. Nothing is deleted when one of them doesn't
+    /// exist. Rebuilds the index, see `add_documents`.
+    pub fn delete_documents(&self, name: &str, doc_ids: &[u32]) -> Result<(), Error> {
+        self.edit_index(name, |builder| {
+            for &doc_id in doc_ids {
+                if !builder.remove_doc(doc_id) {
+                    return Err(no_document(name, doc_id));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Changes the documents of an index with `edit`, then stores the rebuilt
+    /// index and swaps it in. Searches use the previous one until then.
+    ///
+    /// The whole index is rebuilt under the write lock however small the
+    /// change, so the public callers take a batch of changes per call.
+    fn edit_index<T>(
+        &self,
+        name: &str,
+        edit: impl FnOnce(&mut IndexBuilder) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let _guard = self.writes.lock().unwrap();
+        let (index, dir) = match self.indexes.read().unwrap().get(name) {
+            Some(stored) => (stored.index.clone(), stored.dir.clone()),
+            None => return Err(Error::NotFound(format!("no index named `{name}`"))),
+        };
+
+        let mut builder = IndexBuilder::from_index(&index);
+        let value = edit(&mut builder)?;
+        let index = Arc::new(index.with_documents(builder));
+        if let Some(dir) = &dir {
+            store(&index, dir)?;
+        }
+        let stored = StoredIndex { index, dir };
+        self.indexes.write().unwrap().insert(name.to_owned(), stored);
+
+        Ok(value)
+    }
+}
+
+fn no_document(index: &str, doc_id: u32) -> Error {
+    Error::NotFound(format!("no document {doc_id} in index `{index}`"))
+}
+
+fn store(index: &Index, dir: &Path) -> Result<(), Error> {
+    index
+        .save(dir)
+        .map_err(|e| Error::Internal(format!("failed to store the index in {dir:?}: {e}")))
+}
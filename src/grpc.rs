@@ -0,0 +1,342 @@
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use tonic::transport::{server::TcpIncoming, Server};
+use tonic::{Request, Response, Status};
+use type_ahead::storage::index::Index;
+use type_ahead::{Error, NewDocument, SearchRequest, SearchResponse, TypoTolerance, MAX_LIMIT};
+
+use crate::{run_blocking, AppState, DEFAULT_INDEX};
+
+pub mod proto {
+    tonic::include_proto!("type_ahead");
+}
+
+use proto::type_ahead_server::{TypeAhead, TypeAheadServer};
+
+/// Binds the gRPC port before anything is served, so a server that can't
+/// take it fails to start instead of serving HTTP only.
+pub fn bind(addr: SocketAddr) -> Result<TcpIncoming, String> {
+    TcpIncoming::new(addr, true, None).map_err(|e| format!("can't bind gRPC to {addr}: {e}"))
+}
+
+/// Serves `src/proto/search.proto` next to the HTTP router. The whole
+/// server stops if this one does.
+pub async fn serve(state: Arc<AppState>, incoming: TcpIncoming) {
+    println!("gRPC server starting");
+
+    let service = TypeAheadServer::new(GrpcService { state });
+    if let Err(e) = Server::builder()
+        .add_service(service)
+        .serve_with_incoming(incoming)
+        .await
+    {
+        eprintln!("gRPC server failed: {e}");
+        std::process::exit(1);
+    }
+}
+
+struct GrpcService {
+    state: Arc<AppState>,
+}
+
+fn to_status(e: Error) -> Status {
+    if let Error::Internal(_) = e {
+        eprintln!("Internal error: {e}");
+    }
+    match e {
+        Error::BadRequest(message) => Status::invalid_argument(message),
+        Error::NotFound(message) => Status::not_found(message),
+        Error::Conflict(message) => Status::already_exists(message),
+        Error::Internal(message) => Status::internal(message),
+        Error::Cancelled(message) => Status::cancelled(message),
+    }
+}
+
+fn index_name(index: String) -> String {
+    match index.is_empty() {
+        true => DEFAULT_INDEX.to_string(),
+        false => index,
+    }
+}
+
+/// Document `id` of `index` as it is stored.
+fn stored_document(index: &Index, id: u32) -> Option<proto::Document> {
+    let text = index.doc_map.get_text(id)?;
+    let attributes = index
+        .doc_map
+        .attributes_of(id)
+        .map(|attributes| {
+            attributes
+                .fields
+                .iter()
+                .map(|(name, values)| {
+                    let values = values.values.clone();
+                    (name.to_owned(), proto::Values { values })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(proto::Document {
+        id,
+        text: text.to_owned(),
+        boost: index.doc_map.get_boost(id),
+        attributes,
+    })
+}
+
+fn no_document(name: &str, id: u32) -> Status {
+    Status::not_found(format!("no document {id} in index `{name}`"))
+}
+
+impl From<proto::Document> for NewDocument {
+    fn from(doc: proto::Document) -> Self {
+        NewDocument {
+            text: doc.text,
+            boost: Some(doc.boost),
+            attributes: doc
+                .attributes
+                .into_iter()
+                .map(|(name, values)| (name, values.values))
+                .collect(),
+        }
+    }
+}
+
+impl From<proto::SearchRequest> for SearchRequest {
+    fn from(request: proto::SearchRequest) -> Self {
+        SearchRequest {
+            query: request.query,
+            limit: request.limit.map(|limit| limit as usize),
+            offset: request.offset.map(|offset| offset as usize),
+            filter: request.filter,
+            facets: request.facets,
+            sort: request.sort,
+            distinct: request.distinct,
+            attributes_to_retrieve: request.attributes_to_retrieve,
+            highlight: request.highlight,
+            highlight_pre_tag: request.highlight_pre_tag,
+            highlight_post_tag: request.highlight_post_tag,
+            crop: request.crop.map(|crop| crop as usize),
+            crop_marker: request.crop_marker,
+            typo_tolerance: request.typo_tolerance.map(|tolerance| TypoTolerance {
+                enabled: tolerance.enabled,
+                one_typo_min_len: tolerance.one_typo_min_len.map(|len| len as usize),
+                two_typos_min_len: tolerance.two_typos_min_len.map(|len| len as usize),
+            }),
+            ranking_rules: request.ranking_rules,
+        }
+    }
+}
+
+impl From<SearchResponse> for proto::SearchResponse {
+    fn from(result: SearchResponse) -> Self {
+        let mut html_results = result.html_results.map(Vec::into_iter);
+        let mut group_counts = result.group_counts.into_iter();
+        let mut attributes = result.attributes.into_iter();
+
+        let hits = result
+            .ids
+            .into_iter()
+            .zip(result.results)
+            .zip(result.matches)
+            .zip(result.scores)
+            .map(|(((id, text), matches), score)| proto::Hit {
+                id,
+                text,
+                matches: matches
+                    .into_iter()
+                    .map(|span| proto::MatchSpan {
+                        start: span.start as u32,
+                        end: span.end as u32,
+                        char_start: span.char_start as u32,
+                        char_end: span.char_end as u32,
+                        token: span.token,
+                        typos: span.typos as u32,
+                    })
+                    .collect(),
+                html: html_results.as_mut().and_then(Iterator::next),
+                score,
+                attributes: attributes
+                    .next()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, values)| (name, proto::Values { values }))
+                    .collect(),
+                group_count: group_counts.next().map(|count| count as u32),
+            })
+            .collect();
+
+        proto::SearchResponse {
+            query: result.query,
+            hits,
+            total: result.total as u64,
+            facets: result
+                .facets
+                .into_iter()
+                .map(|(name, counts)| {
+                    let counts = counts
+                        .into_iter()
+                        .map(|c| proto::FacetCount {
+                            value: c.value,
+                            count: c.count as u64,
+                        })
+                        .collect();
+                    (name, proto::FacetCounts { counts })
+                })
+                .collect(),
+            next_words: result.next_words,
+            did_you_mean: result.did_you_mean,
+            time: result.time as u64,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl TypeAhead for GrpcService {
+    async fn search(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<proto::SearchResponse>, Status> {
+        let request = request.into_inner();
+        let name = index_name(request.index.clone());
+
+        let state = self.state.clone();
+        let result = run_blocking(move || state.engine.search(&name, request.into()))
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(result.into()))
+    }
+
+    async fn multi_search(
+        &self,
+        request: Request<proto::MultiSearchRequest>,
+    ) -> Result<Response<proto::MultiSearchResponse>, Status> {
+        let queries = request.into_inner().queries;
+        if queries.len() > MAX_LIMIT {
+            return Err(Status::invalid_argument(format!(
+                "a batch can have at most {MAX_LIMIT} queries"
+            )));
+        }
+
+        let t1 = Instant::now();
+        let searches = queries
+            .into_iter()
+            .map(|query| (index_name(query.index.clone()), query.into()))
+            .collect::<Vec<(String, SearchRequest)>>();
+        let names = searches.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+
+        let state = self.state.clone();
+        let results = run_blocking(move || Ok(state.engine.multi_search(searches)))
+            .await
+            .map_err(to_status)?
+            .into_iter()
+            .zip(names)
+            .map(|(result, index)| match result {
+                Ok(result) => proto::MultiSearchItem {
+                    index,
+                    result: Some(result.into()),
+                    error: None,
+                },
+                Err(error) => proto::MultiSearchItem {
+                    index,
+                    result: None,
+                    error: Some(error.to_string()),
+                },
+            })
+            .collect();
+
+        Ok(Response::new(proto::MultiSearchResponse {
+            results,
+            time: (Instant::now() - t1).as_millis() as u64,
+        }))
+    }
+
+    async fn get_document(
+        &self,
+        request: Request<proto::GetDocumentRequest>,
+    ) -> Result<Response<proto::Document>, Status> {
+        let request = request.into_inner();
+        let name = index_name(request.index);
+        let index = self.state.engine.index(&name).map_err(to_status)?;
+
+        stored_document(&index, request.id)
+            .map(Response::new)
+            .ok_or_else(|| no_document(&name, request.id))
+    }
+
+    async fn add_documents(
+        &self,
+        request: Request<proto::AddDocumentsRequest>,
+    ) -> Result<Response<proto::AddDocumentsResponse>, Status> {
+        let request = request.into_inner();
+        let name = index_name(request.index);
+
+        let documents = request.documents.into_iter().map(NewDocument::from).collect();
+
+        let state = self.state.clone();
+        let ids = run_blocking(move || state.engine.add_documents(&name, documents))
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::AddDocumentsResponse { ids }))
+    }
+
+    async fn update_document(
+        &self,
+        request: Request<proto::UpdateDocumentRequest>,
+    ) -> Result<Response<proto::Document>, Status> {
+        let request = request.into_inner();
+        let name = index_name(request.index);
+        let doc = request
+            .document
+            .ok_or_else(|| Status::invalid_argument("`document` is missing"))?;
+
+        let id = doc.id;
+        let state = self.state.clone();
+        let (index, name) = run_blocking(move || {
+            state.engine.replace_documents(&name, vec![(id, doc.into())])?;
+            Ok((state.engine.index(&name)?, name))
+        })
+        .await
+        .map_err(to_status)?;
+
+        stored_document(&index, id)
+            .map(Response::new)
+            .ok_or_else(|| no_document(&name, id))
+    }
+
+    async fn delete_document(
+        &self,
+        request: Request<proto::DeleteDocumentRequest>,
+    ) -> Result<Response<proto::DeleteDocumentResponse>, Status> {
+        let request = request.into_inner();
+        let name = index_name(request.index);
+
+        let state = self.state.clone();
+        run_blocking(move || state.engine.delete_documents(&name, &[request.id]))
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::DeleteDocumentResponse {}))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        let indexes = self
+            .state
+            .engine
+            .indexes()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        Ok(Response::new(proto::HealthResponse {
+            status: "SERVING".to_string(),
+            indexes,
+        }))
+    }
+}
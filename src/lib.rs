@@ -0,0 +1,28 @@
+//! Typo tolerant type-ahead search, served over HTTP and gRPC by the
+//! `type-ahead` binary or embedded through `SearchEngine`:
+//!
+//! ```no_run
+//! use type_ahead::{SearchEngine, SearchRequest};
+//!
+//! # fn main() -> Result<(), type_ahead::Error> {
+//! let engine = SearchEngine::builder()
+//!     .load_index("movies", ".")
+//!     .indexes_dir("indexes")
+//!     .build()?;
+//! let request = SearchRequest {
+//!     query: "lord of the r".to_string(),
+//!     ..Default::default()
+//! };
+//! let response = engine.search("movies", request)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod engine;
+mod search;
+pub mod storage;
+
+pub use engine::{load_message, Error, NewDocument, SearchEngine, SearchEngineBuilder};
+pub use search::{
+    SearchRequest, SearchResponse, SearchSession, TypoTolerance, DEFAULT_LIMIT, MAX_LIMIT,
+};
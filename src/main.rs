@@ -1,91 +1,174 @@
-mod storage;
-use std::collections::{BTreeMap, HashMap};
-use std::io::Read;
-use std::{fs::File, path::Path, sync::Arc, time::Instant};
-
-use edit_distance::edit_distance;
-use fst::automaton::Levenshtein;
-use fst::{IntoStreamer, Set};
-use prost::Message;
-use storage::record::Record;
-use storage::{document::DocumentMap, trie::Trie, word::WordMap};
+mod grpc;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use axum::extract::Query;
+use prost::Message;
+use type_ahead::storage::completion::{Completer, Completion, QueryLog};
+use type_ahead::storage::correction::suggest_correction;
+use type_ahead::storage::feedback::{FeedbackEvent, FeedbackStore};
+use type_ahead::storage::highlight::{MatchSpan, DEFAULT_ALLOWED_TAGS};
+use type_ahead::storage::index::{Index, IndexSettings};
+use type_ahead::{
+    load_message, Error, NewDocument, SearchEngine, SearchRequest, SearchResponse, SearchSession,
+    DEFAULT_LIMIT, MAX_LIMIT,
+};
+
+use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
+use axum::extract::{self, rejection::JsonRejection, Query};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::routing::post;
 use axum::{extract::State, routing::get, Json, Router};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tower_http::cors::CorsLayer;
 
 use serde::Deserialize;
 
-fn load_trie_objects() -> (Trie, WordMap, DocumentMap) {
-    let trie_fname = "./trie.bin";
-    let word_map_fname = "word_map.proto.bin";
-    let doc_map_fname = "doc_map.proto.bin";
-
-    let pwd = std::env::current_dir().unwrap();
+const FEEDBACK_FNAME: &str = "feedback.proto.bin";
+const FEEDBACK_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const FEEDBACK_HALF_LIFE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
-    let word_map_path = pwd.join(word_map_fname);
-    let doc_map_path = pwd.join(doc_map_fname);
-
-    let t1 = Instant::now();
-    println!("trie load time: {}", (Instant::now() - t1).as_millis());
+const QUERY_LOG_FNAME: &str = "query_log.proto.bin";
 
-    let t1 = Instant::now();
-    let mut word_map_file = File::open(word_map_path).unwrap();
-    // let word_map: WordMap =
-    //     bincode::deserialize_from(word_map_file).expect("Failed to deserialize Trie");
+/// The index built from `movie_title_tmdb.txt`, served by `/search`.
+const DEFAULT_INDEX: &str = "default";
+/// Indexes created through the API, one directory per index.
+const INDEXES_DIR: &str = "indexes";
+/// Where the default index is written once its documents change, the build
+/// output in the working directory is left as it is. Remove it to serve a
+/// new build again.
+const DEFAULT_INDEX_DIR: &str = "indexes/default";
 
-    let mut buf = Vec::new();
-    word_map_file
-        .read_to_end(&mut buf)
-        .expect("Failed to read doc_map");
-    let buf = prost::bytes::Bytes::from(buf);
-    let word_map: WordMap = Message::decode(buf).expect("Failed to decode doc_map");
+struct AppState {
+    /// Click feedback, the query log and `/search` belong to the default
+    /// index.
+    engine: SearchEngine,
+    feedback_tx: mpsc::Sender<FeedbackEvent>,
+    completer: RwLock<Completer>,
+}
 
-    println!("word map load time: {}", (Instant::now() - t1).as_millis());
+impl AppState {
+    fn index(&self, name: &str) -> Result<Arc<Index>, (StatusCode, String)> {
+        self.engine.index(name).map_err(http_error)
+    }
 
-    let t1 = Instant::now();
-    let mut doc_map_file = File::open(doc_map_path).unwrap();
-    // let doc_map: DocumentMap =
-    //     bincode::deserialize_from(doc_map_file).expect("Failed to deserialize Trie");
-
-    let mut buf = Vec::new();
-    doc_map_file
-        .read_to_end(&mut buf)
-        .expect("Failed to read doc_map");
-    let buf = prost::bytes::Bytes::from(buf);
-    let doc_map: DocumentMap = Message::decode(buf).expect("Failed to decode doc_map");
-
-    println!("doc map load time: {}", (Instant::now() - t1).as_millis());
-
-    let mut words = vec![];
-    for (w, _) in word_map.word_hash.iter() {
-        words.push(w);
+    fn default_index(&self) -> Arc<Index> {
+        self.index(DEFAULT_INDEX).unwrap()
     }
+}
 
-    let trie = Trie::new(words);
+fn http_error(e: Error) -> (StatusCode, String) {
+    if let Error::Internal(_) = e {
+        eprintln!("Internal error: {e}");
+    }
+    let status = match e {
+        Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::Conflict(_) => StatusCode::CONFLICT,
+        Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        // As nginx answers requests given up on by their client.
+        Error::Cancelled(_) => StatusCode::from_u16(499).unwrap(),
+    };
+    (status, e.to_string())
+}
 
-    (trie, word_map, doc_map)
+/// Runs engine work that may block off the async workers. A panic in it
+/// fails only this call, as an internal error.
+async fn run_blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(Error::Internal(format!("background task failed: {e}"))))
 }
 
-struct AppState {
-    trie: Trie,
-    word_map: WordMap,
-    doc_map: DocumentMap,
+/// The server can't start without its files and ports.
+fn exit_on_start_error<T>(e: Error) -> T {
+    eprintln!("Failed to start: {e}");
+    std::process::exit(1)
 }
 
 #[tokio::main]
 async fn main() {
-    let (trie, word_map, doc_map) = load_trie_objects();
+    let engine = SearchEngine::builder()
+        .seed_index(DEFAULT_INDEX, DEFAULT_INDEX_DIR, ".")
+        .indexes_dir(INDEXES_DIR)
+        .highlight_tags(&std::env::var("HIGHLIGHT_TAGS").unwrap_or(DEFAULT_ALLOWED_TAGS.to_string()))
+        .build()
+        .unwrap_or_else(exit_on_start_error);
+    let names = engine.indexes().into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+    println!("Serving indexes: {}", names.join(", "));
+    *engine.index(DEFAULT_INDEX).unwrap().feedback.write().unwrap() = load_message(FEEDBACK_FNAME)
+        .unwrap_or_else(exit_on_start_error)
+        .unwrap_or_else(FeedbackStore::new);
+
+    let (feedback_tx, feedback_rx) = mpsc::channel(10_000);
 
     let shared_state = Arc::new(AppState {
-        trie,
-        word_map,
-        doc_map,
+        engine,
+        feedback_tx,
+        completer: RwLock::new(Completer::new(
+            load_message(QUERY_LOG_FNAME)
+                .unwrap_or_else(exit_on_start_error)
+                .unwrap_or_else(QueryLog::new),
+        )),
     });
 
+    tokio::spawn(aggregate_feedback(shared_state.clone(), feedback_rx));
+
+    let grpc_port = std::env::var("GRPC_PORT").unwrap_or("50051".to_string());
+    let grpc_incoming = grpc::bind(format!("0.0.0.0:{grpc_port}").parse().unwrap())
+        .unwrap_or_else(|e| exit_on_start_error(Error::Internal(e)));
+    tokio::spawn(grpc::serve(shared_state.clone(), grpc_incoming));
+
     let app = Router::new()
         .route("/", get(|| async { "How u doing" }))
-        .route("/search", get(search).with_state(shared_state))
+        .route(
+            "/search",
+            get(search).post(search_post).with_state(shared_state.clone()),
+        )
+        .route("/ws", get(search_ws).with_state(shared_state.clone()))
+        .route(
+            "/multi-search",
+            post(multi_search).with_state(shared_state.clone()),
+        )
+        .route(
+            "/federated-search",
+            post(federated_search).with_state(shared_state.clone()),
+        )
+        .route(
+            "/indexes",
+            get(list_indexes)
+                .post(create_index)
+                .with_state(shared_state.clone()),
+        )
+        .route(
+            "/indexes/:name",
+            get(index_info)
+                .delete(delete_index)
+                .with_state(shared_state.clone()),
+        )
+        .route(
+            "/indexes/:name/search",
+            get(index_search)
+                .post(index_search_post)
+                .with_state(shared_state.clone()),
+        )
+        .route(
+            "/indexes/:name/ws",
+            get(index_search_ws).with_state(shared_state.clone()),
+        )
+        .route(
+            "/suggest-correction",
+            get(correction).with_state(shared_state.clone()),
+        )
+        .route("/complete", get(complete).with_state(shared_state.clone()))
+        .route("/query-log", post(add_query_log).with_state(shared_state.clone()))
+        .route("/feedback", post(feedback).with_state(shared_state))
         .layer(CorsLayer::permissive());
 
     println!("Server starting");
@@ -101,52 +184,631 @@ async fn main() {
 #[derive(Deserialize, Debug)]
 struct SearchQuery {
     query: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    /// Render `html_results`, on by default.
+    highlight: Option<bool>,
+    highlight_pre_tag: Option<String>,
+    highlight_post_tag: Option<String>,
+    /// Crop every result to this many words around the best match.
+    crop: Option<usize>,
+    crop_marker: Option<String>,
+    /// Attribute filter, e.g. `year >= 2000 AND language = en`.
+    filter: Option<String>,
+    /// Comma separated facet fields to count over all matches.
+    facets: Option<String>,
+    /// Sort rules instead of relevance, e.g. `year:desc,title:asc`.
+    sort: Option<String>,
+    /// Collapse results to one per `title` (normalized) or attribute value.
+    distinct: Option<String>,
+    /// Comma separated attributes returned with every result, `*` for all.
+    attributes_to_retrieve: Option<String>,
+}
+
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl From<SearchQuery> for SearchRequest {
+    fn from(query: SearchQuery) -> Self {
+        SearchRequest {
+            query: query.query.unwrap_or_default(),
+            // Too large limits are clamped rather than rejected here, as
+            // they always have been.
+            limit: query.limit.map(|limit| limit.min(MAX_LIMIT)),
+            offset: query.offset,
+            filter: query.filter,
+            facets: split_list(query.facets),
+            sort: split_list(query.sort),
+            distinct: query.distinct,
+            attributes_to_retrieve: split_list(query.attributes_to_retrieve),
+            highlight: query.highlight,
+            highlight_pre_tag: query.highlight_pre_tag,
+            highlight_post_tag: query.highlight_post_tag,
+            crop: query.crop,
+            crop_marker: query.crop_marker,
+            typo_tolerance: None,
+            ranking_rules: vec![],
+        }
+    }
+}
+
+async fn search(
+    Query(query): Query<SearchQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    let request = query.into();
+    run_blocking(move || state.engine.search(DEFAULT_INDEX, request))
+        .await
+        .map(Json)
+        .map_err(http_error)
+}
+
+async fn search_post(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<SearchRequest>, JsonRejection>,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    let Json(request) = request.map_err(|e| (StatusCode::BAD_REQUEST, e.body_text()))?;
+    run_blocking(move || state.engine.search(DEFAULT_INDEX, request))
+        .await
+        .map(Json)
+        .map_err(http_error)
+}
+
+async fn index_search(
+    extract::Path(name): extract::Path<String>,
+    Query(query): Query<SearchQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    let request = query.into();
+    run_blocking(move || state.engine.search(&name, request))
+        .await
+        .map(Json)
+        .map_err(http_error)
+}
+
+async fn index_search_post(
+    extract::Path(name): extract::Path<String>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<SearchRequest>, JsonRejection>,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    let Json(request) = request.map_err(|e| (StatusCode::BAD_REQUEST, e.body_text()))?;
+    run_blocking(move || state.engine.search(&name, request))
+        .await
+        .map(Json)
+        .map_err(http_error)
+}
+
+async fn search_ws(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+    ws.on_upgrade(move |socket| search_session(socket, state, DEFAULT_INDEX.to_string()))
+}
+
+async fn index_search_ws(
+    extract::Path(name): extract::Path<String>,
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, (StatusCode, String)> {
+    state.index(&name)?;
+    Ok(ws.on_upgrade(move |socket| search_session(socket, state, name)))
+}
+
+/// Reply to the `seq`th message of a session, either `result` or `error`
+/// is set.
+#[derive(serde::Serialize)]
+struct SessionReply {
+    seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<SearchResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+type SearchTask = JoinHandle<Result<SearchResponse, (StatusCode, String)>>;
+
+/// A message is the query itself, or a `POST /search` body when it starts
+/// with `{`.
+fn parse_session_message(text: &str) -> Result<SearchRequest, (StatusCode, String)> {
+    match text.trim_start().starts_with('{') {
+        true => serde_json::from_str(text).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string())),
+        false => Ok(SearchRequest {
+            query: text.to_owned(),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Searches the messages of a type-ahead session as they come, one at a
+/// time. A message arriving while a search runs cancels it, messages
+/// replace each other so only the latest is searched next, and the result
+/// of a search overtaken by a newer message is never sent.
+async fn search_session(mut socket: WebSocket, state: Arc<AppState>, index: String) {
+    let session = Arc::new(SearchSession::default());
+    let mut seq = 0;
+    let mut pending: Option<(u64, String)> = None;
+    let mut running: Option<(u64, SearchTask)> = None;
+
+    loop {
+        if running.is_none() {
+            if let Some((next, text)) = pending.take() {
+                let state = state.clone();
+                let index = index.clone();
+                let session = session.clone();
+                session.resume();
+                let task = tokio::task::spawn_blocking(move || {
+                    let request = parse_session_message(&text)?;
+                    state
+                        .engine
+                        .session_search(&session, &index, request)
+                        .map_err(http_error)
+                });
+                running = Some((next, task));
+            }
+        }
+
+        tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(ws::Message::Text(text))) => {
+                    seq += 1;
+                    pending = Some((seq, text));
+                    if running.is_some() {
+                        session.cancel();
+                    }
+                }
+                Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            result = async { (&mut running.as_mut().unwrap().1).await }, if running.is_some() => {
+                let (seq, _) = running.take().unwrap();
+                if pending.is_some() {
+                    continue;
+                }
+                let result = result.unwrap_or_else(|e| {
+                    Err((StatusCode::INTERNAL_SERVER_ERROR, format!("search failed: {e}")))
+                });
+                let reply = match result {
+                    Ok(result) => SessionReply {
+                        seq,
+                        result: Some(result),
+                        status: None,
+                        error: None,
+                    },
+                    Err((status, error)) => SessionReply {
+                        seq,
+                        result: None,
+                        status: Some(status.as_u16()),
+                        error: Some(error),
+                    },
+                };
+                let reply = serde_json::to_string(&reply).unwrap();
+                if socket.send(ws::Message::Text(reply)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MultiSearchEntry {
+    /// The default index when left out.
+    index: Option<String>,
+    #[serde(default)]
+    search: SearchRequest,
+}
+
+#[derive(Deserialize)]
+struct MultiSearchQuery {
+    queries: Vec<MultiSearchEntry>,
+}
+
+/// Result of one entry of a batch, either `result` or `error` is set.
+#[derive(serde::Serialize)]
+struct MultiSearchItem {
+    index: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<SearchResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct MultiSearchResult {
+    results: Vec<MultiSearchItem>,
+    time: u128,
+}
+
+/// Runs a batch of searches concurrently, see `SearchEngine::multi_search`.
+async fn multi_search(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<MultiSearchQuery>, JsonRejection>,
+) -> Result<Json<MultiSearchResult>, (StatusCode, String)> {
+    let Json(request) = request.map_err(|e| (StatusCode::BAD_REQUEST, e.body_text()))?;
+    if request.queries.len() > MAX_LIMIT {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("a batch can have at most {MAX_LIMIT} queries"),
+        ));
+    }
+
+    let t1 = Instant::now();
+    let (names, searches): (Vec<String>, Vec<(String, SearchRequest)>) = request
+        .queries
+        .into_iter()
+        .map(|entry| {
+            let index = entry.index.unwrap_or(DEFAULT_INDEX.to_string());
+            (index.clone(), (index, entry.search))
+        })
+        .unzip();
+
+    let batch = run_blocking(move || Ok(state.engine.multi_search(searches)))
+        .await
+        .map_err(http_error)?;
+
+    let mut results = vec![];
+    for (index, result) in names.into_iter().zip(batch) {
+        let item = match result.map_err(http_error) {
+            Ok(result) => MultiSearchItem {
+                index,
+                result: Some(result),
+                status: None,
+                error: None,
+            },
+            Err((status, error)) => MultiSearchItem {
+                index,
+                result: None,
+                status: Some(status.as_u16()),
+                error: Some(error),
+            },
+        };
+        results.push(item);
+    }
+
+    Ok(Json(MultiSearchResult {
+        results,
+        time: (Instant::now() - t1).as_millis(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct FederatedIndex {
+    name: String,
+    /// Multiplies the scores of this index, 1 by default.
+    weight: Option<f64>,
+    /// Most results taken from this index, `limit` by default.
+    quota: Option<usize>,
+    filter: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FederatedQuery {
+    query: String,
+    limit: Option<usize>,
+    highlight: Option<bool>,
+    indexes: Vec<FederatedIndex>,
 }
 
 #[derive(serde::Serialize)]
-struct SearchResult {
+struct FederatedHit {
+    /// The index the result comes from.
+    index: String,
+    id: u32,
+    result: String,
+    matches: Vec<MatchSpan>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html_result: Option<String>,
+    /// Weighted `Record::score`, comparable across indexes.
+    score: f64,
+}
+
+#[derive(serde::Serialize)]
+struct FederatedResult {
     query: String,
-    results: Vec<String>,
-    html_results: Vec<String>,
+    hits: Vec<FederatedHit>,
+    /// Matching documents per index.
+    totals: BTreeMap<String, usize>,
     time: u128,
 }
 
-async fn search(
+/// Searches several indexes at once and interleaves their results by
+/// weighted score. Every index keeps its own order, the merge only decides
+/// whose next result comes first.
+async fn federated_search(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<FederatedQuery>, JsonRejection>,
+) -> Result<Json<FederatedResult>, (StatusCode, String)> {
+    let Json(request) = request.map_err(|e| (StatusCode::BAD_REQUEST, e.body_text()))?;
+    let t1 = Instant::now();
+    let limit = request.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    if request.indexes.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "no indexes to search".to_string()));
+    }
+
+    let mut searches = vec![];
+    for entry in &request.indexes {
+        let weight = entry.weight.unwrap_or(1.0);
+        if !weight.is_finite() || weight < 0.0 {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{}: weight must be a positive number", entry.name),
+            ));
+        }
+        if searches.iter().any(|(name, _)| *name == entry.name) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("index `{}` is listed twice", entry.name),
+            ));
+        }
+
+        state.index(&entry.name)?;
+        let query = SearchRequest {
+            query: request.query.clone(),
+            limit: Some(entry.quota.unwrap_or(limit).min(limit)),
+            highlight: request.highlight,
+            filter: entry.filter.clone(),
+            ..Default::default()
+        };
+        searches.push((entry.name.clone(), query));
+    }
+
+    let results = run_blocking(move || Ok(state.engine.multi_search(searches)))
+        .await
+        .map_err(http_error)?;
+
+    let mut sources = vec![];
+    let mut totals = BTreeMap::new();
+    for (entry, result) in request.indexes.iter().zip(results) {
+        let weight = entry.weight.unwrap_or(1.0);
+        let result = result.map_err(|e| {
+            let (status, e) = http_error(e);
+            (status, format!("{}: {e}", entry.name))
+        })?;
+        totals.insert(entry.name.clone(), result.total);
+
+        let mut html_results = result.html_results.map(Vec::into_iter);
+        let hits = result
+            .ids
+            .into_iter()
+            .zip(result.results)
+            .zip(result.matches)
+            .zip(result.scores)
+            .map(|(((id, text), matches), score)| FederatedHit {
+                index: entry.name.clone(),
+                id,
+                result: text,
+                matches,
+                html_result: html_results.as_mut().and_then(Iterator::next),
+                score: weight * score,
+            })
+            .collect::<Vec<FederatedHit>>();
+        sources.push(hits.into_iter().peekable());
+    }
+
+    let mut hits = vec![];
+    while hits.len() < limit {
+        // Ties go to the index listed first.
+        let next = sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, source)| source.peek().map(|hit| (i, hit.score)))
+            .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(&a.0)));
+        match next {
+            Some((i, _)) => hits.extend(sources[i].next()),
+            None => break,
+        }
+    }
+
+    Ok(Json(FederatedResult {
+        query: request.query,
+        hits,
+        totals,
+        time: (Instant::now() - t1).as_millis(),
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct CorrectionResult {
+    query: String,
+    suggestion: Option<String>,
+}
+
+async fn correction(
     Query(query): Query<SearchQuery>,
     State(state): State<Arc<AppState>>,
-) -> Json<SearchResult> {
+) -> Result<Json<CorrectionResult>, (StatusCode, String)> {
+    let query = query.query.unwrap_or_default().to_lowercase();
+    let index = state.default_index();
+    run_blocking(move || {
+        let normalized = index.analyzer.normalize(&query);
+        let suggestion = suggest_correction(&index.trie, &index.word_map, &normalized)?;
+        Ok(Json(CorrectionResult { query, suggestion }))
+    })
+    .await
+    .map_err(http_error)
+}
+
+#[derive(serde::Serialize)]
+struct CompletionResult {
+    query: String,
+    completions: Vec<Completion>,
+}
+
+async fn complete(
+    Query(query): Query<SearchQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Json<CompletionResult> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
     let query = query.query.unwrap_or_default();
+    let completions = state.completer.read().unwrap().complete(&query, limit);
 
-    let query = query.to_lowercase();
+    Json(CompletionResult { query, completions })
+}
 
-    let t1 = Instant::now();
+#[derive(Deserialize)]
+struct QueryLogEntry {
+    query: String,
+    count: Option<u64>,
+}
 
-    let trie = &state.trie;
-    let word_map = &state.word_map;
-    let doc_map = &state.doc_map;
+async fn add_query_log(
+    State(state): State<Arc<AppState>>,
+    Json(entries): Json<Vec<QueryLogEntry>>,
+) -> StatusCode {
+    let queries = entries
+        .into_iter()
+        .map(|e| (e.query, e.count.unwrap_or(1)))
+        .collect::<Vec<(String, u64)>>();
+
+    let buf = {
+        let mut completer = state.completer.write().unwrap();
+        completer.add_queries(&queries);
+        Message::encode_to_vec(completer.log())
+    };
+
+    match tokio::fs::write(QUERY_LOG_FNAME, buf).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            eprintln!("Failed to write query_log: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
 
-    let similar_doc_ids = trie.find_matches(&query, &word_map);
+async fn feedback(
+    State(state): State<Arc<AppState>>,
+    Json(event): Json<FeedbackEvent>,
+) -> StatusCode {
+    if !state.default_index().doc_map.contains_doc(event.doc_id) {
+        return StatusCode::NOT_FOUND;
+    }
 
-    println!("similar_doc_ids len {}", similar_doc_ids.len());
+    match state.feedback_tx.try_send(event) {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
 
-    let _t1 = Instant::now();
-    let search = doc_map.sort_raw_result(&query, &similar_doc_ids);
-    let _t2 = Instant::now();
-    println!("sorting res: {}", (_t2 - _t1).as_millis());
+#[derive(Deserialize)]
+struct CreateIndex {
+    name: String,
+    #[serde(default)]
+    settings: IndexSettings,
+    #[serde(default)]
+    documents: Vec<NewDocument>,
+}
 
-    let t2 = Instant::now();
+#[derive(serde::Serialize)]
+struct IndexInfo {
+    name: String,
+    documents: usize,
+    settings: IndexSettings,
+}
 
-    // println!("{} ms ", (t2 - t1).as_millis());
+impl IndexInfo {
+    fn new(name: &str, index: &Index) -> Self {
+        IndexInfo {
+            name: name.to_owned(),
+            documents: index.doc_map.doc_ids().count(),
+            settings: index.settings.clone(),
+        }
+    }
+}
 
-    // Create a JSON response
-    let results = search.iter().map(|r| r.1.to_owned()).collect();
-    let html_results = search.iter().map(|r| r.0.to_owned()).collect();
-    Json(SearchResult {
-        query,
-        results,
-        html_results,
-        time: (t2 - t1).as_millis(),
+async fn list_indexes(State(state): State<Arc<AppState>>) -> Json<Vec<IndexInfo>> {
+    let indexes = state
+        .engine
+        .indexes()
+        .iter()
+        .map(|(name, index)| IndexInfo::new(name, index))
+        .collect();
+
+    Json(indexes)
+}
+
+async fn index_info(
+    extract::Path(name): extract::Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<IndexInfo>, (StatusCode, String)> {
+    let index = state.index(&name)?;
+    Ok(Json(IndexInfo::new(&name, &index)))
+}
+
+/// Builds an index from the posted documents and stores it under
+/// `indexes/<name>`, where it is loaded from on the next start.
+async fn create_index(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CreateIndex>,
+) -> Result<(StatusCode, Json<IndexInfo>), (StatusCode, String)> {
+    let CreateIndex {
+        name,
+        settings,
+        documents,
+    } = request;
+
+    let info = run_blocking(move || {
+        let index = state.engine.create_index(&name, settings, documents)?;
+        Ok(IndexInfo::new(&name, &index))
     })
+    .await
+    .map_err(http_error)?;
+
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn delete_index(
+    extract::Path(name): extract::Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if name == DEFAULT_INDEX {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "the default index can't be deleted".to_string(),
+        ));
+    }
+
+    run_blocking(move || state.engine.delete_index(&name))
+        .await
+        .map_err(http_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Buffers click events and periodically folds them into the decayed
+/// popularity store, which is then persisted next to the index files.
+async fn aggregate_feedback(state: Arc<AppState>, mut rx: mpsc::Receiver<FeedbackEvent>) {
+    let decay = 0.5_f64
+        .powf(FEEDBACK_FLUSH_INTERVAL.as_secs_f64() / FEEDBACK_HALF_LIFE.as_secs_f64());
+    let mut interval = tokio::time::interval(FEEDBACK_FLUSH_INTERVAL);
+    let mut events = vec![];
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => events.push(event),
+                None => break,
+            },
+            _ = interval.tick() => {
+                let index = state.default_index();
+                let buf = {
+                    let mut feedback = index.feedback.write().unwrap();
+                    feedback.apply(&events, decay);
+                    Message::encode_to_vec(&*feedback)
+                };
+                events.clear();
+
+                if let Err(e) = tokio::fs::write(FEEDBACK_FNAME, buf).await {
+                    eprintln!("Failed to write feedback: {e}");
+                }
+            }
+        }
+    }
 }
 
 // fn main() {
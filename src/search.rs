@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::storage::correction::suggest_correction;
+use crate::storage::facet::FacetCount;
+use crate::storage::filter::parse_filter;
+use crate::storage::highlight::{
+    crop, HighlightFormatter, MatchSpan, TagAllowlist, DEFAULT_CROP_MARKER, DEFAULT_POST_TAG,
+    DEFAULT_PRE_TAG,
+};
+use crate::storage::index::Index;
+use crate::storage::record::{QueryWords, RankingRule, Record, DEFAULT_RANKING_RULES};
+use crate::storage::sort::{parse_sort, SortRule};
+use crate::storage::trie::{MatchError, SessionMatches, TopMatches, TypoPolicy};
+use crate::Error;
+
+pub const DEFAULT_LIMIT: usize = 10;
+pub const MAX_LIMIT: usize = 100;
+const NEXT_WORDS_LIMIT: usize = 5;
+
+/// Per-request override of the typo policy of an index.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TypoTolerance {
+    pub enabled: Option<bool>,
+    pub one_typo_min_len: Option<usize>,
+    pub two_typos_min_len: Option<usize>,
+}
+
+/// Options of a search, also the body of `POST /search`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchRequest {
+    pub query: String,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub filter: Option<String>,
+    pub facets: Vec<String>,
+    /// e.g. `["year:desc", "title:asc"]`.
+    pub sort: Vec<String>,
+    pub distinct: Option<String>,
+    pub attributes_to_retrieve: Vec<String>,
+    pub highlight: Option<bool>,
+    pub highlight_pre_tag: Option<String>,
+    pub highlight_post_tag: Option<String>,
+    pub crop: Option<usize>,
+    pub crop_marker: Option<String>,
+    pub typo_tolerance: Option<TypoTolerance>,
+    /// Replaces the ranking rules of the index for this search.
+    pub ranking_rules: Vec<String>,
+}
+
+/// Results of a search, the body of `/search` responses.
+#[derive(serde::Serialize)]
+pub struct SearchResponse {
+    pub query: String,
+    pub ids: Vec<u32>,
+    pub results: Vec<String>,
+    pub matches: Vec<Vec<MatchSpan>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html_results: Option<Vec<String>>,
+    pub total: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub facets: BTreeMap<String, Vec<FacetCount>>,
+    /// Matching documents in the group of each result, with `distinct`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub group_counts: Vec<usize>,
+    /// Attributes of each result, with `attributes_to_retrieve`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attributes: Vec<BTreeMap<String, Vec<String>>>,
+    /// Likely next words once the last query word is a complete word.
+    pub next_words: Vec<String>,
+    /// Corrected query, only set when nothing matched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub did_you_mean: Option<String>,
+    pub time: u128,
+    /// `Record::score` of each result, for merging with other indexes.
+    #[serde(skip)]
+    pub scores: Vec<f64>,
+}
+
+/// What decides the candidate set of a search.
+#[derive(Hash, PartialEq, Eq)]
+pub(crate) struct CandidateKey {
+    trie: u32,
+    query: String,
+    filter: Option<String>,
+    typos: TypoPolicy,
+    k: usize,
+}
+
+/// Candidate sets shared by the searches of one batch, see
+/// `SearchEngine::multi_search`.
+pub(crate) type CandidateCache = Mutex<HashMap<CandidateKey, Arc<TopMatches>>>;
+
+/// State of a type-ahead session, whose searches run one at a time and
+/// each replace the one before, see `SearchEngine::session_search`. The
+/// matches of the query words before the last are kept for the next
+/// search, which usually only changes the last word.
+#[derive(Default)]
+pub struct SearchSession {
+    matches: SessionMatches,
+}
+
+impl SearchSession {
+    /// Stops the running search of the session, which then fails with
+    /// `Error::Cancelled`, as does a search that was started but is not
+    /// running yet.
+    pub fn cancel(&self) {
+        self.matches.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// Lets the searches of the session run again after `cancel`. Called by
+    /// whoever starts the next search, before starting it, so a `cancel`
+    /// coming in between is not lost.
+    pub fn resume(&self) {
+        self.matches.cancel.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Earlier work a search can build on.
+#[derive(Clone, Copy)]
+pub(crate) enum Reuse<'a> {
+    Nothing,
+    Batch(&'a CandidateCache),
+    Session(&'a SearchSession),
+}
+
+impl From<MatchError> for Error {
+    fn from(e: MatchError) -> Self {
+        match e {
+            MatchError::Cancelled => Error::Cancelled("replaced by a newer search".to_string()),
+            MatchError::WordTooLong(word) => {
+                Error::BadRequest(format!("query word `{word}` is too long"))
+            }
+        }
+    }
+}
+
+pub(crate) fn search_index(
+    index: &Index,
+    highlight_tags: &TagAllowlist,
+    request: SearchRequest,
+    reuse: Reuse,
+) -> Result<SearchResponse, Error> {
+    let session = match reuse {
+        Reuse::Session(session) => Some(&session.matches),
+        _ => None,
+    };
+
+    let limit = request.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit > MAX_LIMIT {
+        return Err(Error::BadRequest(format!(
+            "limit can be at most {MAX_LIMIT}, got {limit}"
+        )));
+    }
+    let offset = request.offset.unwrap_or(0);
+    let highlight = request.highlight.unwrap_or(true);
+    // The default tags are our own, only the ones picked by the caller are
+    // checked.
+    let custom_tags =
+        request.highlight_pre_tag.is_some() || request.highlight_post_tag.is_some();
+    let pre_tag = request.highlight_pre_tag.unwrap_or(DEFAULT_PRE_TAG.to_string());
+    let post_tag = request.highlight_post_tag.unwrap_or(DEFAULT_POST_TAG.to_string());
+    let crop_words = request.crop;
+    let crop_marker = request.crop_marker.unwrap_or(DEFAULT_CROP_MARKER.to_string());
+    if highlight && custom_tags {
+        highlight_tags
+            .validate(&pre_tag, &post_tag)
+            .map_err(Error::BadRequest)?;
+    }
+    let filter = match &request.filter {
+        Some(expr) => Some(
+            parse_filter(expr)
+                .and_then(|filter| index.filter_index.evaluate(&filter))
+                .map_err(|e| Error::BadRequest(format!("invalid filter: {e}")))?,
+        ),
+        None => None,
+    };
+    let facet_fields = request.facets;
+    if let Some(field) = facet_fields.iter().find(|f| !index.facet_index.has_field(f)) {
+        return Err(Error::BadRequest(format!("`{field}` is not a facet field")));
+    }
+    let sort_rules = match request.sort.is_empty() {
+        false => parse_sort(&request.sort.join(","))
+            .and_then(|rules| index.sort_index.validate(&rules).map(|_| rules))
+            .map_err(|e| Error::BadRequest(format!("invalid sort: {e}")))?,
+        true => vec![SortRule::Relevance],
+    };
+    if let Some(field) = request.distinct.as_deref() {
+        if !index.distinct_index.has_field(field) {
+            return Err(Error::BadRequest(format!("`{field}` is not a distinct field")));
+        }
+    }
+    let distinct = request.distinct;
+    let retrieve = request.attributes_to_retrieve;
+    if let Some(name) = retrieve
+        .iter()
+        .find(|name| *name != "*" && index.doc_map.attribute_kind(name).is_none())
+    {
+        return Err(Error::BadRequest(format!("unknown attribute `{name}`")));
+    }
+    let mut typos = index.trie.typo_policy();
+    if let Some(tolerance) = request.typo_tolerance {
+        if tolerance.enabled == Some(false) {
+            typos.one_typo_min_len = usize::MAX;
+            typos.two_typos_min_len = usize::MAX;
+        }
+        typos.one_typo_min_len = tolerance.one_typo_min_len.unwrap_or(typos.one_typo_min_len);
+        typos.two_typos_min_len = tolerance.two_typos_min_len.unwrap_or(typos.two_typos_min_len);
+        if typos.one_typo_min_len > typos.two_typos_min_len {
+            return Err(Error::BadRequest(
+                "one_typo_min_len can't be larger than two_typos_min_len".to_string(),
+            ));
+        }
+    }
+    let ranking_rules = match request.ranking_rules.is_empty() {
+        true => index.ranking_rules.clone(),
+        false => request
+            .ranking_rules
+            .iter()
+            .map(|rule| RankingRule::parse(rule))
+            .collect::<Result<Vec<RankingRule>, String>>()
+            .map_err(Error::BadRequest)?,
+    };
+
+    let query = request.query.to_lowercase();
+    // Matched the way the documents were indexed, so "Amélie" finds "amelie".
+    let normalized = index.analyzer.normalize(&query);
+
+    let t1 = Instant::now();
+
+    let trie = &index.trie;
+    let word_map = &index.word_map;
+    let doc_map = &index.doc_map;
+
+    // Facets and groups are counted over every match, and attribute sorts or
+    // other ranking rules can rank any match first, so candidate generation
+    // can't stop early for them.
+    let early = facet_fields.is_empty() && distinct.is_none();
+    let ranked = sort_rules[0] == SortRule::Relevance && ranking_rules == DEFAULT_RANKING_RULES;
+    let k = match early && ranked {
+        true => offset.saturating_add(limit),
+        false => usize::MAX,
+    };
+    let key = CandidateKey {
+        trie: trie.id(),
+        query: normalized.clone(),
+        filter: request.filter,
+        typos,
+        k,
+    };
+    let cached = match reuse {
+        Reuse::Batch(candidates) => candidates.lock().unwrap().get(&key).cloned(),
+        _ => None,
+    };
+    let top_matches = match cached {
+        Some(top_matches) => top_matches,
+        None => {
+            let top_matches = Arc::new(trie.find_top_matches(
+                &normalized,
+                word_map,
+                k,
+                filter.as_ref(),
+                &typos,
+                session,
+            )?);
+            if let Reuse::Batch(candidates) = reuse {
+                candidates.lock().unwrap().insert(key, top_matches.clone());
+            }
+            top_matches
+        }
+    };
+    let similar_doc_ids = &top_matches.docs;
+    let facets = index.facet_index.count(&facet_fields, similar_doc_ids.keys());
+
+    let words = normalized.split_whitespace().collect::<Vec<&str>>();
+    let next_words = match words.last() {
+        Some(last) if word_map.word_hash.contains_key(*last) => {
+            index.successors.predict(&words, NEXT_WORDS_LIMIT)
+        }
+        _ => vec![],
+    };
+
+    let did_you_mean = if similar_doc_ids.is_empty() {
+        suggest_correction(trie, word_map, &normalized)?
+    } else {
+        None
+    };
+
+    // Ranking is the other costly step.
+    if session.is_some_and(|s| s.cancel.load(Ordering::Relaxed)) {
+        return Err(MatchError::Cancelled.into());
+    }
+
+    let query_words = QueryWords::new(&query, index.analyzer);
+    let (search, group_counts, total) = {
+        let feedback = index.feedback.read().unwrap();
+        let order = |a: &Record, b: &Record| {
+            index.sort_index.compare(&sort_rules, &ranking_rules, a, b)
+        };
+        match &distinct {
+            Some(field) => {
+                let ranked = doc_map.sort_raw_result(
+                    &query_words,
+                    similar_doc_ids,
+                    &feedback,
+                    0,
+                    usize::MAX,
+                    order,
+                );
+                let total = index.distinct_index.count(field, similar_doc_ids.keys());
+                let (search, group_counts) = index
+                    .distinct_index
+                    .collapse(field, ranked, offset, limit)
+                    .into_iter()
+                    .map(|d| (d.record, d.group_count))
+                    .unzip();
+                (search, group_counts, total)
+            }
+            None => (
+                doc_map.sort_raw_result(
+                    &query_words,
+                    similar_doc_ids,
+                    &feedback,
+                    offset,
+                    limit,
+                    order,
+                ),
+                vec![],
+                top_matches.total,
+            ),
+        }
+    };
+    let t2 = Instant::now();
+
+    // println!("{} ms ", (t2 - t1).as_millis());
+
+    // Create a JSON response
+    let ids = search.iter().map(|r| r.doc_id).collect();
+    let scores = search.iter().map(|r| r.score(index.max_boost)).collect();
+    let attributes = match retrieve.is_empty() {
+        true => vec![],
+        false => search
+            .iter()
+            .map(|r| {
+                index
+                    .doc_map
+                    .attributes_of(r.doc_id)
+                    .map(|attributes| {
+                        attributes
+                            .fields
+                            .iter()
+                            .filter(|(name, _)| retrieve.iter().any(|f| f == "*" || f == *name))
+                            .map(|(name, values)| (name.to_owned(), values.values.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            })
+            .collect(),
+    };
+    let (results, matches): (Vec<String>, Vec<Vec<MatchSpan>>) = search
+        .iter()
+        .map(|r| {
+            let spans = r.match_spans(index.analyzer, &typos);
+            match crop_words {
+                Some(nr_words) => crop(&r.record, &spans, &query_words, nr_words, &crop_marker),
+                None => (r.record.to_owned(), spans),
+            }
+        })
+        .unzip();
+    let html_results = highlight.then(|| {
+        let formatter = HighlightFormatter {
+            pre_tag: &pre_tag,
+            post_tag: &post_tag,
+        };
+        results
+            .iter()
+            .zip(&matches)
+            .map(|(text, spans)| formatter.format(text, spans))
+            .collect()
+    });
+    Ok(SearchResponse {
+        query,
+        ids,
+        results,
+        matches,
+        html_results,
+        total,
+        facets,
+        group_counts,
+        attributes,
+        next_words,
+        did_you_mean,
+        time: (t2 - t1).as_millis(),
+        scores,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{search_index, Reuse, SearchRequest};
+    use crate::storage::document::{Analyzer, Attributes};
+    use crate::storage::highlight::TagAllowlist;
+    use crate::storage::index::{Index, IndexBuilder, IndexSettings};
+
+    fn results(index: &Index, query: &str) -> Vec<String> {
+        let request = SearchRequest {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        search_index(index, &TagAllowlist::new(""), request, Reuse::Nothing)
+            .unwrap()
+            .results
+    }
+
+    /// The query is folded like the titles, so an accented word is an exact
+    /// match and not one typo away from its unaccented neighbour.
+    #[test]
+    fn accented_queries_match_like_the_index() {
+        let mut builder = IndexBuilder::new(Analyzer::Standard);
+        builder.add_doc("Emelie", 10, Attributes::default());
+        builder.add_doc("Amélie", 0, Attributes::default());
+        let (word_map, doc_map, successors) = builder.build("");
+        let index = Index::new(IndexSettings::default(), word_map, doc_map, successors);
+
+        assert_eq!(results(&index, "amélie"), ["Amélie", "Emelie"]);
+        assert_eq!(results(&index, "AMÉLIE:"), ["Amélie", "Emelie"]);
+    }
+}
@@ -1,12 +1,23 @@
 use lazy_static::lazy_static;
 use lru::LruCache;
-use std::{num::NonZeroUsize, sync::Mutex};
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use super::trie::{MatchesKey, PartialMatches};
 
 lazy_static! {
     pub static ref CACHE: Mutex<LruCache<String, Vec<(String, usize)>>> = {
         let size = NonZeroUsize::new(100).unwrap();
         Mutex::new(LruCache::new(size))
     };
+    /// Intersected matches of the first words of recent queries, see
+    /// `Trie::find_matches`.
+    pub static ref MATCHES_CACHE: Mutex<LruCache<MatchesKey, Arc<PartialMatches>>> = {
+        let size = NonZeroUsize::new(100).unwrap();
+        Mutex::new(LruCache::new(size))
+    };
 }
 
 pub fn insert_into_cache(key: &str, value: &Vec<(String, usize)>) {
@@ -18,4 +29,14 @@ pub fn insert_into_cache(key: &str, value: &Vec<(String, usize)>) {
 pub fn retrieve_from_cache(key: &str) -> Option<Vec<(String, usize)>> {
     let mut cache = CACHE.lock().unwrap();
     cache.get(key).cloned()
-}
\ No newline at end of file
+}
+
+pub fn insert_matches_into_cache(key: MatchesKey, value: Arc<PartialMatches>) {
+    let mut cache = MATCHES_CACHE.lock().unwrap();
+    cache.put(key, value);
+}
+
+pub fn retrieve_matches_from_cache(key: &MatchesKey) -> Option<Arc<PartialMatches>> {
+    let mut cache = MATCHES_CACHE.lock().unwrap();
+    cache.get(key).cloned()
+}
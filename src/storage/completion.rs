@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use fst::{
+    automaton::{Levenshtein, Str},
+    Automaton, IntoStreamer, Map,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use super::{document::tokenize, trie::allowed_errors};
+
+/// How often each normalized query was searched for.
+#[derive(Serialize, Deserialize, Message)]
+pub struct QueryLog {
+    #[prost(map = "string, uint64", tag = "1")]
+    pub counts: HashMap<String, u64>,
+}
+
+impl QueryLog {
+    pub fn new() -> Self {
+        QueryLog {
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, query: &str, count: u64) {
+        let query = normalize_query(query);
+        if !query.is_empty() {
+            *self.counts.entry(query).or_insert(0) += count;
+        }
+    }
+}
+
+/// Same normalization as the indexed documents, with words joined by a
+/// single space.
+fn normalize_query(query: &str) -> String {
+    tokenize(query)
+        .into_iter()
+        .map(|token| token.text)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub query: String,
+    pub count: u64,
+}
+
+/// Whole query completions from the query log, served from an fst map of
+/// normalized query to count.
+pub struct Completer {
+    log: QueryLog,
+    fst: Map<Vec<u8>>,
+}
+
+impl Completer {
+    pub fn new(log: QueryLog) -> Self {
+        let fst = Self::build_fst(&log);
+        Completer { log, fst }
+    }
+
+    fn build_fst(log: &QueryLog) -> Map<Vec<u8>> {
+        let mut entries = log.counts.iter().collect::<Vec<(&String, &u64)>>();
+        entries.sort();
+        Map::from_iter(entries.into_iter().map(|(query, count)| (query, *count))).unwrap()
+    }
+
+    pub fn log(&self) -> &QueryLog {
+        &self.log
+    }
+
+    /// Folds more queries into the log and rebuilds the fst.
+    pub fn add_queries(&mut self, queries: &[(String, u64)]) {
+        for (query, count) in queries {
+            self.log.add(query, *count);
+        }
+        self.fst = Self::build_fst(&self.log);
+    }
+
+    /// Logged queries starting with `prefix`, allowing a few typos on longer
+    /// prefixes. Exact prefix matches come first, then the most frequent.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<Completion> {
+        let mut normalized = normalize_query(prefix);
+        if normalized.is_empty() {
+            return vec![];
+        }
+        // "star " ranks "star wars" as an exact prefix match, above "stargate".
+        if prefix.ends_with(char::is_whitespace) {
+            normalized.push(' ');
+        }
+
+        let k = allowed_errors(&normalized).min(normalized.len() / 4) as u32;
+        let matches = match Levenshtein::new(&normalized, k) {
+            Ok(lev) => self.fst.search(lev.starts_with()).into_stream().into_str_vec(),
+            Err(_) => self
+                .fst
+                .search(Str::new(&normalized).starts_with())
+                .into_stream()
+                .into_str_vec(),
+        };
+        let mut completions = matches
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(query, count)| Completion { query, count })
+            .collect::<Vec<Completion>>();
+
+        completions.sort_by(|a, b| {
+            b.query
+                .starts_with(&normalized)
+                .cmp(&a.query.starts_with(&normalized))
+                .then_with(|| b.count.cmp(&a.count))
+                .then_with(|| a.query.cmp(&b.query))
+        });
+        completions.truncate(limit);
+        completions
+    }
+}
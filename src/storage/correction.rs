@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+
+use super::{
+    document::tokenize,
+    trie::{MatchError, Trie},
+    word::WordMap,
+};
+
+/// Typos tolerated per token when looking for corrections. Wider than the
+/// search itself, a correction is only needed when the search came up empty.
+const MAX_CORRECTION_ERRORS: usize = 2;
+/// Candidate words kept per query token, by their own score.
+const CANDIDATES_PER_TOKEN: usize = 5;
+/// Partial corrections kept while walking the query tokens.
+const BEAM_WIDTH: usize = 5;
+/// Score lost per typo, against `ln(1 + popularity)` gained.
+const TYPO_PENALTY: f64 = 2.0;
+/// Weight of `ln(1 + documents containing all words so far)`.
+const CO_OCCURRENCE_WEIGHT: f64 = 1.0;
+
+struct Candidate {
+    words: Vec<String>,
+    docs: HashSet<u32>,
+    score: f64,
+}
+
+/// Builds a "did you mean" query: every token is replaced by one of its
+/// closest vocabulary words, preferring popular words and words that appear
+/// together in the same documents. Returns `None` when no better query than
+/// the one given is found, and fails on query words too long to correct.
+pub fn suggest_correction(
+    trie: &Trie,
+    word_map: &WordMap,
+    query: &str,
+) -> Result<Option<String>, MatchError> {
+    let tokens = tokenize(query);
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut beam = vec![Candidate {
+        words: vec![],
+        docs: HashSet::new(),
+        score: 0.0,
+    }];
+
+    for token in &tokens {
+        let k = MAX_CORRECTION_ERRORS.min(token.text.chars().count().saturating_sub(1));
+        let mut words = trie
+            .find_words(&token.text, k)?
+            .into_iter()
+            .map(|(word, edits)| {
+                let popularity = word_map.get_word(&word).popularity as f64;
+                let score = (1.0 + popularity).ln() - TYPO_PENALTY * edits as f64;
+                (word, score)
+            })
+            .collect::<Vec<(String, f64)>>();
+        if words.is_empty() {
+            return Ok(None);
+        }
+        words.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        words.truncate(CANDIDATES_PER_TOKEN);
+
+        let mut next_beam = vec![];
+        for candidate in &beam {
+            for (word, word_score) in &words {
+                let word_docs = word_map
+                    .get_word(word)
+                    .in_records
+                    .iter()
+                    .map(|rec| rec.idx);
+                let docs = if candidate.words.is_empty() {
+                    word_docs.collect::<HashSet<u32>>()
+                } else {
+                    word_docs.filter(|idx| candidate.docs.contains(idx)).collect()
+                };
+
+                let mut words = candidate.words.clone();
+                words.push(word.to_owned());
+                next_beam.push(Candidate {
+                    score: candidate.score + word_score,
+                    words,
+                    docs,
+                });
+            }
+        }
+
+        next_beam.sort_by(|a, b| {
+            let a_score = a.score + CO_OCCURRENCE_WEIGHT * (1.0 + a.docs.len() as f64).ln();
+            let b_score = b.score + CO_OCCURRENCE_WEIGHT * (1.0 + b.docs.len() as f64).ln();
+            b_score
+                .total_cmp(&a_score)
+                .then_with(|| a.words.cmp(&b.words))
+        });
+        next_beam.truncate(BEAM_WIDTH);
+        beam = next_beam;
+    }
+
+    // A correction that still finds nothing is not worth suggesting.
+    let Some(best) = beam.into_iter().find(|candidate| !candidate.docs.is_empty()) else {
+        return Ok(None);
+    };
+    let suggestion = best.words.join(" ");
+
+    let normalized = tokens
+        .iter()
+        .map(|token| token.text.as_str())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if suggestion == normalized {
+        return Ok(None);
+    }
+
+    Ok(Some(suggestion))
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    document::{set_doc_value, tokenize, DocumentMap},
+    record::Record,
+};
+
+/// Grouping by the normalized document text rather than an attribute.
+pub const TITLE_GROUP: &str = "title";
+
+/// "The Thing (1982)", "The Thing [Remastered]" and "the thing" all
+/// normalize to "the thing".
+pub fn normalize_title(title: &str) -> String {
+    let mut stripped = String::with_capacity(title.len());
+    let mut depth = 0;
+    for c in title.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(c),
+            _ => {}
+        }
+    }
+
+    tokenize(&stripped)
+        .into_iter()
+        .map(|token| token.text)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// A result kept by `DistinctIndex::collapse` and how many matching
+/// documents share its group, itself included.
+pub struct DistinctRecord {
+    pub record: Record,
+    pub group_count: usize,
+}
+
+/// Group id of every document for the normalized title and for the first
+/// value of each attribute. Documents without the attribute are never
+/// grouped together.
+pub struct DistinctIndex {
+    groups: HashMap<String, Vec<Option<u32>>>,
+}
+
+impl DistinctIndex {
+    pub fn new(doc_map: &DocumentMap) -> Self {
+        let mut keys = vec![];
+        for doc_id in doc_map.doc_ids() {
+            if let Some(title) = doc_map.get_text(doc_id) {
+                keys.push((TITLE_GROUP, doc_id, normalize_title(title)));
+            }
+        }
+        for (doc_id, attributes) in doc_map.attributes() {
+            for (name, values) in &attributes.fields {
+                if let Some(value) = values.values.first() {
+                    keys.push((name.as_str(), doc_id, value.trim().to_lowercase()));
+                }
+            }
+        }
+
+        let mut groups = HashMap::new();
+        let mut dictionaries: HashMap<&str, HashMap<String, u32>> = HashMap::new();
+        for (field, doc_id, key) in keys {
+            let dictionary = dictionaries.entry(field).or_default();
+            let next = dictionary.len() as u32;
+            let group = *dictionary.entry(key).or_insert(next);
+
+            let docs: &mut Vec<Option<u32>> = groups.entry(field.to_owned()).or_default();
+            set_doc_value(docs, doc_id, Some(group));
+        }
+
+        DistinctIndex { groups }
+    }
+
+    pub fn has_field(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+    }
+
+    fn group(&self, field: &str, doc_id: u32) -> Option<u32> {
+        self.groups.get(field)?.get(doc_id as usize).copied().flatten()
+    }
+
+    /// Number of groups among `docs`.
+    pub fn count<'a>(&self, field: &str, docs: impl Iterator<Item = &'a u32>) -> usize {
+        let mut seen = HashSet::new();
+        let mut ungrouped = 0;
+        for doc_id in docs {
+            match self.group(field, *doc_id) {
+                Some(group) => {
+                    seen.insert(group);
+                }
+                None => ungrouped += 1,
+            }
+        }
+        seen.len() + ungrouped
+    }
+
+    /// Keeps the first, i.e. best ranked, of every group in `ranked` and
+    /// returns the page of groups starting at `offset`.
+    pub fn collapse(
+        &self,
+        field: &str,
+        ranked: Vec<Record>,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<DistinctRecord> {
+        let mut counts = HashMap::new();
+        for r in &ranked {
+            if let Some(group) = self.group(field, r.doc_id) {
+                *counts.entry(group).or_insert(0) += 1;
+            }
+        }
+
+        let mut kept = HashSet::new();
+        ranked
+            .into_iter()
+            .filter_map(|record| match self.group(field, record.doc_id) {
+                Some(group) => kept.insert(group).then(|| DistinctRecord {
+                    record,
+                    group_count: counts[&group],
+                }),
+                None => Some(DistinctRecord {
+                    record,
+                    group_count: 1,
+                }),
+            })
+            .skip(offset)
+            .take(limit)
+            .collect()
+    }
+}
@@ -1,36 +1,162 @@
-use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicU32, Ordering},
-    usize,
-};
+use std::{cmp::min, collections::HashMap, usize};
 
 use prost::Message;
 use serde::{Deserialize, Serialize};
-use unidecode::unidecode;
+use unidecode::unidecode_char;
 
-use super::{record::Record, word::{WordMap, WordInRecord}};
+use super::{
+    facet::FacetField,
+    feedback::FeedbackStore,
+    record::{QueryWords, Record},
+    word::{WordMap, WordInRecord},
+};
 
-static DOCUMENT_COUNTER: AtomicU32 = AtomicU32::new(0);
 
-#[derive(Serialize, Deserialize, Message)]
+/// Type of a document attribute, decides which filter operators apply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum AttributeKind {
+    String = 0,
+    Integer = 1,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Message)]
+pub struct AttributeValues {
+    #[prost(string, repeated, tag = "1")]
+    pub values: Vec<String>,
+}
+
+/// Attribute name to its values, attributes like `genre` can have several.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Message)]
+pub struct Attributes {
+    #[prost(map = "string, message", tag = "1")]
+    pub fields: HashMap<String, AttributeValues>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Message)]
 pub struct DocumentMap {
     #[prost(map = "uint32, string", tag = "1")]
     document_map: HashMap<u32, String>,
+    /// Per-document popularity signal (vote count, revenue, click score, ...).
+    /// Documents without an entry have a boost of 0.
+    #[prost(map = "uint32, uint32", tag = "2")]
+    boost_map: HashMap<u32, u32>,
+    /// Typed attributes of each document, e.g. `year` or `genre`.
+    #[prost(map = "uint32, message", tag = "3")]
+    attributes: HashMap<u32, Attributes>,
+    #[prost(map = "string, enumeration(AttributeKind)", tag = "4")]
+    schema: HashMap<String, i32>,
+    /// Attributes counted in `facets` of search responses.
+    #[prost(message, repeated, tag = "5")]
+    facet_fields: Vec<FacetField>,
+    /// Ids are assigned per map, so every index numbers its documents from 0.
+    #[prost(uint32, tag = "6")]
+    next_doc_id: u32,
 }
 
 impl DocumentMap {
     pub fn new() -> Self {
         DocumentMap {
             document_map: HashMap::new(),
+            boost_map: HashMap::new(),
+            attributes: HashMap::new(),
+            schema: HashMap::new(),
+            facet_fields: vec![],
+            next_doc_id: 0,
         }
     }
 
-    pub fn add_doc(&mut self, text: String) -> Document {
-        let id = DOCUMENT_COUNTER.fetch_add(1, Ordering::SeqCst);
+    pub fn add_doc(&mut self, text: String, boost: u32, attributes: Attributes) -> Document {
+        let id = self.next_doc_id;
+        self.next_doc_id += 1;
+        self.insert_doc(id, text, boost, attributes)
+    }
+
+    /// Puts a document under an id given out by `add_doc`, e.g. to replace
+    /// a removed one.
+    pub fn insert_doc(
+        &mut self,
+        id: u32,
+        text: String,
+        boost: u32,
+        attributes: Attributes,
+    ) -> Document {
         self.document_map.insert(id, text.clone());
+        if boost > 0 {
+            self.boost_map.insert(id, boost);
+        }
+        if !attributes.fields.is_empty() {
+            self.attributes.insert(id, attributes);
+        }
         Document { id, text }
     }
 
+    /// Drops a document, its id isn't given out again.
+    pub fn remove_doc(&mut self, doc_id: u32) -> bool {
+        self.boost_map.remove(&doc_id);
+        self.attributes.remove(&doc_id);
+        self.document_map.remove(&doc_id).is_some()
+    }
+
+    /// Types every attribute seen so far: `Integer` when all of its values
+    /// parse as one, `String` otherwise.
+    pub fn infer_schema(&mut self) {
+        self.schema.clear();
+        for attributes in self.attributes.values() {
+            for (name, values) in &attributes.fields {
+                let is_integer = values.values.iter().all(|v| v.trim().parse::<i64>().is_ok());
+                let kind = match self.schema.get(name) {
+                    Some(kind) if *kind == AttributeKind::String as i32 => AttributeKind::String,
+                    _ if is_integer => AttributeKind::Integer,
+                    _ => AttributeKind::String,
+                };
+                self.schema.insert(name.to_owned(), kind as i32);
+            }
+        }
+    }
+
+    pub fn set_facet_fields(&mut self, facet_fields: Vec<FacetField>) {
+        self.facet_fields = facet_fields;
+    }
+
+    pub fn facet_fields(&self) -> &[FacetField] {
+        &self.facet_fields
+    }
+
+    pub fn attribute_kind(&self, name: &str) -> Option<AttributeKind> {
+        self.schema
+            .get(name)
+            .map(|kind| AttributeKind::try_from(*kind).unwrap_or(AttributeKind::String))
+    }
+
+    pub fn doc_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.document_map.keys().copied()
+    }
+
+    pub fn attributes(&self) -> impl Iterator<Item = (u32, &Attributes)> {
+        self.attributes.iter().map(|(id, attributes)| (*id, attributes))
+    }
+
+    pub fn attributes_of(&self, doc_id: u32) -> Option<&Attributes> {
+        self.attributes.get(&doc_id)
+    }
+
+    pub fn contains_doc(&self, doc_id: u32) -> bool {
+        self.document_map.contains_key(&doc_id)
+    }
+
+    pub fn max_boost(&self) -> u32 {
+        self.boost_map.values().copied().max().unwrap_or(0)
+    }
+
+    pub fn get_boost(&self, doc_id: u32) -> u32 {
+        *self.boost_map.get(&doc_id).unwrap_or(&0)
+    }
+
+    pub fn get_text(&self, doc_id: u32) -> Option<&str> {
+        self.document_map.get(&doc_id).map(String::as_str)
+    }
+
     pub fn get_document(&self, doc_id: &Vec<u32>) -> Vec<String> {
         doc_id
             .iter()
@@ -38,45 +164,58 @@ impl DocumentMap {
             .collect()
     }
 
-    pub fn sort_raw_result<'a>(
+    /// Ranks the candidates by `order`, e.g. `Record::rank_cmp`, and returns
+    /// the page starting at `offset`. `query` must be tokenized with the
+    /// analyzer the documents were indexed with.
+    pub fn sort_raw_result<F>(
         &self,
-        query: &'a str,
+        query: &QueryWords,
         similar_map: &HashMap<u32, (usize, usize)>,
-    ) -> Vec<(String, String)> {
-        let mut query_pos = HashMap::new();
-        for (idx, wi) in query.split_whitespace().enumerate() {
-            query_pos.entry(wi.to_string()).or_insert(vec![]).push(idx);
-        }
-
-        let query_pos = query_pos
-            .iter()
-            .map(|(k, v)| (k.to_owned(), v.to_owned()))
-            .collect::<HashMap<String, Vec<usize>>>();
-
+        feedback: &FeedbackStore,
+        offset: usize,
+        limit: usize,
+        order: F,
+    ) -> Vec<Record>
+    where
+        F: Fn(&Record, &Record) -> std::cmp::Ordering,
+    {
         let mut matches = vec![];
 
-        for (record, (nr_matches, edit)) in similar_map.iter() {
-            let record = self.document_map.get(&record).unwrap();
-            println!("record: {record:?} - {:?}", (nr_matches, edit));
+        for (id, (nr_matches, edit)) in similar_map.iter() {
+            let record = self.document_map.get(id).unwrap();
 
-            matches.push(Record::new(query, &query_pos, &record, *nr_matches, *edit));
+            let mut r = Record::new(query, record, *nr_matches, *edit);
+            r.doc_id = *id;
+            r.boost = self.get_boost(*id);
+            r.popularity = feedback.popularity(query.text(), *id);
+            matches.push(r);
         }
 
-        matches.sort_by(|a, b| {
-            if a.exact_matches != b.exact_matches {
-                return b.exact_matches.cmp(&a.exact_matches);
-            }
-            // a.operations.cmp(&b.operations)
+        // Only the first `offset + limit` records need a full sort, pull them
+        // to the front with a partial selection first.
+        let end = min(offset.saturating_add(limit), matches.len());
+        if end == 0 {
+            return vec![];
+        }
+        if end < matches.len() {
+            matches.select_nth_unstable_by(end - 1, &order);
+            matches.truncate(end);
+        }
+        matches.sort_by(&order);
 
-            a.calculate_distance().total_cmp(&b.calculate_distance())
-        });
+        matches.into_iter().skip(offset).collect()
+    }
+}
 
-        matches
-            .iter()
-            .map(|ele| (ele.updated_record.clone(), ele.record.to_owned()) )
-            .take(10)
-            .collect()
+/// Sets the value of `doc_id` in a column indexed by document id, as kept
+/// by the indexes derived from a `DocumentMap`. Ids left out in between get
+/// the default value.
+pub fn set_doc_value<T: Clone + Default>(column: &mut Vec<T>, doc_id: u32, value: T) {
+    let doc_id = doc_id as usize;
+    if column.len() <= doc_id {
+        column.resize(doc_id + 1, T::default());
     }
+    column[doc_id] = value;
 }
 
 #[derive(Serialize, Deserialize, Message)]
@@ -88,28 +227,105 @@ pub struct Document {
     // word_index: Vec<usize>,
 }
 
-impl Document {
-    pub fn process(&self, word_map: &mut WordMap) {
-        let text = unidecode(&self.text);
+/// A normalized word of a document together with the byte range of the
+/// original text it was produced from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
 
-        let text = text.to_lowercase();
+/// Characters dropped from words when indexing.
+fn is_stripped(c: char) -> bool {
+    // '-' is kept, splitting on it would break words like "spider-man".
+    matches!(
+        c,
+        '\'' | ':' | '.' | ',' | '*' | '+' | '?' | '$' | '{' | '}' | '(' | ')' | '|'
+    )
+}
+
+/// How an index turns text into words, chosen per index.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Analyzer {
+    /// Transliterated to ASCII, "Amélie" is indexed as "amelie".
+    #[default]
+    Standard,
+    /// Keeps accents, for catalogs where they tell words apart.
+    Accented,
+}
 
-        let mut result_text = String::new();
+impl Analyzer {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "" | "standard" => Ok(Analyzer::Standard),
+            "accented" => Ok(Analyzer::Accented),
+            _ => Err(format!("unknown analyzer `{name}`")),
+        }
+    }
 
-        for c in text.chars() {
-            match c {
-                '\'' | ':' | '.' | ',' | '*' | '+' | '?' | '$' | '{' | '}' | '(' | ')' | '|' => {}
-                // '-' => {
-                //     result_text.push(' '); // Replace '-' with a whitespace
-                // }
-                _ => {
-                    result_text.push(c); // Keep all other characters
+    /// Splits `text` into the words the index is built from: lowercased,
+    /// stripped of punctuation and, for `Standard`, transliterated to ASCII.
+    /// Every token remembers the span of original characters it came from,
+    /// so matches on the normalized form can be mapped back onto the text
+    /// as it is displayed.
+    pub fn tokenize(self, text: &str) -> Vec<Token> {
+        let mut tokens = vec![];
+        let mut current: Option<Token> = None;
+        let mut buf = [0; 4];
+
+        for (idx, c) in text.char_indices() {
+            let end = idx + c.len_utf8();
+            let folded = match self {
+                Analyzer::Standard => unidecode_char(c),
+                Analyzer::Accented => c.encode_utf8(&mut buf),
+            };
+
+            for n in folded.chars().flat_map(char::to_lowercase) {
+                if n.is_whitespace() {
+                    tokens.extend(current.take());
+                } else if !is_stripped(n) {
+                    match current.as_mut() {
+                        Some(token) => {
+                            token.text.push(n);
+                            token.end = end;
+                        }
+                        None => {
+                            current = Some(Token {
+                                text: n.to_string(),
+                                start: idx,
+                                end,
+                            })
+                        }
+                    }
                 }
             }
         }
+        tokens.extend(current);
+
+        tokens
+    }
+
+    /// `text` as the words the index matches, separated by single spaces.
+    pub fn normalize(self, text: &str) -> String {
+        let tokens = self.tokenize(text);
+        tokens.iter().map(|t| t.text.as_str()).collect::<Vec<&str>>().join(" ")
+    }
+}
+
+/// Tokenizes with the `Standard` analyzer.
+pub fn tokenize(text: &str) -> Vec<Token> {
+    Analyzer::Standard.tokenize(text)
+}
+
+impl Document {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
 
-        for (pos, e) in result_text.split_whitespace().enumerate() {
-            let mut word = word_map.get_or_create_word_mut(e);
+    pub fn process(&self, word_map: &mut WordMap, analyzer: Analyzer) {
+        for (pos, token) in analyzer.tokenize(&self.text).iter().enumerate() {
+            let mut word = word_map.get_or_create_word_mut(&token.text);
 
             word.in_records.push( WordInRecord { idx: self.id, pos: pos as u32} );
             word.position.push(pos as u32);
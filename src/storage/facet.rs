@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap};
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use super::document::{set_doc_value, DocumentMap};
+
+/// Facet values returned per field, the most frequent first.
+const MAX_FACET_VALUES: usize = 20;
+
+/// An attribute declared as a facet in the index schema. Integer attributes
+/// can be counted in buckets, e.g. `year` with a bucket of 10 for decades.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Message)]
+pub struct FacetField {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(int64, tag = "2")]
+    pub bucket: i64,
+}
+
+/// Parses a facet declaration like `genre,language,year:10`.
+pub fn parse_facet_fields(spec: &str) -> Vec<FacetField> {
+    spec.split(',')
+        .filter(|field| !field.trim().is_empty())
+        .map(|field| match field.split_once(':') {
+            Some((name, bucket)) => FacetField {
+                name: name.trim().to_owned(),
+                bucket: bucket.trim().parse().unwrap_or(0),
+            },
+            None => FacetField {
+                name: field.trim().to_owned(),
+                bucket: 0,
+            },
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: usize,
+}
+
+/// Facet values of every document for one field, dictionary encoded.
+struct FacetColumn {
+    name: String,
+    values: Vec<String>,
+    docs: Vec<Vec<u32>>,
+}
+
+/// Facet values by column, so counting a candidate set is a few array
+/// lookups per document.
+pub struct FacetIndex {
+    columns: Vec<FacetColumn>,
+}
+
+impl FacetIndex {
+    pub fn new(doc_map: &DocumentMap) -> Self {
+        let mut columns = vec![];
+
+        for field in doc_map.facet_fields() {
+            let mut dictionary = HashMap::new();
+            let mut column = FacetColumn {
+                name: field.name.to_owned(),
+                values: vec![],
+                docs: vec![],
+            };
+
+            for (doc_id, attributes) in doc_map.attributes() {
+                let values = match attributes.fields.get(&field.name) {
+                    Some(values) => values,
+                    None => continue,
+                };
+
+                let mut value_ids = vec![];
+                for value in &values.values {
+                    let value = match (field.bucket, value.trim().parse::<i64>()) {
+                        (0, _) => value.trim().to_owned(),
+                        (bucket, Ok(v)) => (v.div_euclid(bucket) * bucket).to_string(),
+                        (_, Err(_)) => continue,
+                    };
+                    let value_id = *dictionary.entry(value.clone()).or_insert_with(|| {
+                        column.values.push(value);
+                        column.values.len() as u32 - 1
+                    });
+                    if !value_ids.contains(&value_id) {
+                        value_ids.push(value_id);
+                    }
+                }
+
+                set_doc_value(&mut column.docs, doc_id, value_ids);
+            }
+
+            columns.push(column);
+        }
+
+        FacetIndex { columns }
+    }
+
+    pub fn has_field(&self, name: &str) -> bool {
+        self.columns.iter().any(|column| column.name == name)
+    }
+
+    /// Value counts of the requested facet `fields` over `docs`.
+    pub fn count<'a, I>(&self, fields: &[String], docs: I) -> BTreeMap<String, Vec<FacetCount>>
+    where
+        I: Iterator<Item = &'a u32> + Clone,
+    {
+        let mut facets = BTreeMap::new();
+
+        for column in self.columns.iter().filter(|c| fields.contains(&c.name)) {
+            let mut counts = vec![0; column.values.len()];
+            for doc_id in docs.clone() {
+                if let Some(value_ids) = column.docs.get(*doc_id as usize) {
+                    for value_id in value_ids {
+                        counts[*value_id as usize] += 1;
+                    }
+                }
+            }
+
+            let mut values = counts
+                .into_iter()
+                .enumerate()
+                .filter(|(_, count)| *count > 0)
+                .map(|(value_id, count)| FacetCount {
+                    value: column.values[value_id].to_owned(),
+                    count,
+                })
+                .collect::<Vec<FacetCount>>();
+            values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+            values.truncate(MAX_FACET_VALUES);
+
+            facets.insert(column.name.to_owned(), values);
+        }
+
+        facets
+    }
+}
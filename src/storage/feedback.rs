@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+/// Scores below this are dropped during aggregation so that documents nobody
+/// clicks on any more eventually disappear from the store.
+const MIN_SCORE: f64 = 0.01;
+
+/// A single click on a suggestion: the user typed `query` and picked
+/// `doc_id`, which was shown at (zero based) `position` in the result list.
+#[derive(Serialize, Deserialize, Message)]
+pub struct FeedbackEvent {
+    #[prost(string, tag = "1")]
+    pub query: String,
+    #[prost(uint32, tag = "2")]
+    pub doc_id: u32,
+    #[prost(uint32, tag = "3")]
+    pub position: u32,
+}
+
+impl FeedbackEvent {
+    /// Clicks further down the list carry more signal than clicks on the top
+    /// suggestion, which users pick partly because it is on top.
+    fn weight(&self) -> f64 {
+        1.0 + (1.0 + self.position as f64).ln()
+    }
+}
+
+/// Exponentially decayed click popularity, per document and per
+/// (query, document) pair.
+#[derive(Clone, Serialize, Deserialize, Message)]
+pub struct FeedbackStore {
+    #[prost(map = "uint32, double", tag = "1")]
+    doc_popularity: HashMap<u32, f64>,
+    #[prost(map = "string, double", tag = "2")]
+    query_doc_popularity: HashMap<String, f64>,
+}
+
+impl FeedbackStore {
+    pub fn new() -> Self {
+        FeedbackStore {
+            doc_popularity: HashMap::new(),
+            query_doc_popularity: HashMap::new(),
+        }
+    }
+
+    fn query_doc_key(query: &str, doc_id: u32) -> String {
+        let query = query
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        format!("{query}::{doc_id}")
+    }
+
+    /// Multiplies every existing score by `decay` and then folds `events` in.
+    pub fn apply(&mut self, events: &[FeedbackEvent], decay: f64) {
+        for score in self.doc_popularity.values_mut() {
+            *score *= decay;
+        }
+        for score in self.query_doc_popularity.values_mut() {
+            *score *= decay;
+        }
+
+        for event in events {
+            let weight = event.weight();
+            *self.doc_popularity.entry(event.doc_id).or_insert(0.0) += weight;
+            *self
+                .query_doc_popularity
+                .entry(Self::query_doc_key(&event.query, event.doc_id))
+                .or_insert(0.0) += weight;
+        }
+
+        self.doc_popularity.retain(|_, score| *score >= MIN_SCORE);
+        self.query_doc_popularity.retain(|_, score| *score >= MIN_SCORE);
+    }
+
+    /// Learned popularity of `doc_id` for `query`. Clicks for the exact same
+    /// query count on top of the document's overall popularity.
+    pub fn popularity(&self, query: &str, doc_id: u32) -> f64 {
+        let doc = self.doc_popularity.get(&doc_id).unwrap_or(&0.0);
+        let query_doc = self
+            .query_doc_popularity
+            .get(&Self::query_doc_key(query, doc_id))
+            .unwrap_or(&0.0);
+        doc + query_doc
+    }
+}
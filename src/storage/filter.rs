@@ -0,0 +1,403 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+};
+
+use roaring::RoaringBitmap;
+
+use super::document::{AttributeKind, DocumentMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+/// Parsed `filter` expression, e.g. `year >= 2000 AND (genre = drama OR NOT language = en)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Condition {
+        field: String,
+        op: Operator,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FilterToken {
+    Open,
+    Close,
+    Op(Operator),
+    Word { text: String, quoted: bool },
+}
+
+fn lex(expr: &str) -> Result<Vec<FilterToken>, String> {
+    let mut tokens = vec![];
+    let mut chars = expr.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { FilterToken::Open } else { FilterToken::Close });
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if_eq(&'=').is_some();
+                let op = match (c, eq) {
+                    ('=', _) => Operator::Eq,
+                    ('!', true) => Operator::Ne,
+                    ('>', false) => Operator::Gt,
+                    ('>', true) => Operator::Ge,
+                    ('<', false) => Operator::Lt,
+                    ('<', true) => Operator::Le,
+                    _ => return Err("expected `!=`".to_string()),
+                };
+                tokens.push(FilterToken::Op(op));
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => text.push(ch),
+                        None => return Err("unterminated quoted value".to_string()),
+                    }
+                }
+                tokens.push(FilterToken::Word { text, quoted: true });
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || "()=!<>\"'".contains(ch) {
+                        break;
+                    }
+                    text.push(ch);
+                    chars.next();
+                }
+                tokens.push(FilterToken::Word {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<FilterToken>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(
+            self.tokens.get(self.pos),
+            Some(FilterToken::Word { text, quoted: false }) if text.eq_ignore_ascii_case(keyword)
+        )
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.parse_not()?;
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.parse_not()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_not(&mut self) -> Result<Filter, String> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Filter::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Filter, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(FilterToken::Open) => {
+                self.pos += 1;
+                let filter = self.parse_or()?;
+                match self.tokens.get(self.pos) {
+                    Some(FilterToken::Close) => {
+                        self.pos += 1;
+                        Ok(filter)
+                    }
+                    _ => Err("expected `)`".to_string()),
+                }
+            }
+            Some(FilterToken::Word { text: field, .. }) => {
+                let op = match self.tokens.get(self.pos + 1) {
+                    Some(FilterToken::Op(op)) => *op,
+                    _ => return Err(format!("expected an operator after `{field}`")),
+                };
+                let value = match self.tokens.get(self.pos + 2) {
+                    Some(FilterToken::Word { text, .. }) => text.to_owned(),
+                    _ => return Err(format!("expected a value after `{field}`")),
+                };
+                self.pos += 3;
+                Ok(Filter::Condition { field, op, value })
+            }
+            _ => Err("expected a condition".to_string()),
+        }
+    }
+}
+
+pub fn parse_filter(expr: &str) -> Result<Filter, String> {
+    let mut parser = Parser {
+        tokens: lex(expr)?,
+        pos: 0,
+    };
+
+    let filter = parser.parse_or()?;
+    if parser.pos != parser.tokens.len() {
+        return Err("unexpected trailing input".to_string());
+    }
+    Ok(filter)
+}
+
+enum FieldBitmaps {
+    Integer(BTreeMap<i64, RoaringBitmap>),
+    String(HashMap<String, RoaringBitmap>),
+}
+
+/// Bitmap of matching documents per attribute value, integer values in
+/// order for range conditions.
+pub struct FilterIndex {
+    all: RoaringBitmap,
+    fields: HashMap<String, FieldBitmaps>,
+}
+
+impl FilterIndex {
+    pub fn new(doc_map: &DocumentMap) -> Self {
+        let mut fields = HashMap::new();
+
+        for (doc_id, attributes) in doc_map.attributes() {
+            for (name, values) in &attributes.fields {
+                let field = fields.entry(name.to_owned()).or_insert_with(|| {
+                    match doc_map.attribute_kind(name) {
+                        Some(AttributeKind::Integer) => FieldBitmaps::Integer(BTreeMap::new()),
+                        _ => FieldBitmaps::String(HashMap::new()),
+                    }
+                });
+
+                for value in &values.values {
+                    let bitmap = match field {
+                        FieldBitmaps::Integer(map) => match value.trim().parse() {
+                            Ok(value) => map.entry(value).or_default(),
+                            Err(_) => continue,
+                        },
+                        FieldBitmaps::String(map) => {
+                            map.entry(value.trim().to_lowercase()).or_default()
+                        }
+                    };
+                    bitmap.insert(doc_id);
+                }
+            }
+        }
+
+        FilterIndex {
+            all: doc_map.doc_ids().collect(),
+            fields,
+        }
+    }
+
+    /// Documents matching `filter`, or a description of why it can't be applied.
+    pub fn evaluate(&self, filter: &Filter) -> Result<RoaringBitmap, String> {
+        match filter {
+            Filter::And(a, b) => Ok(self.evaluate(a)? & self.evaluate(b)?),
+            Filter::Or(a, b) => Ok(self.evaluate(a)? | self.evaluate(b)?),
+            Filter::Not(a) => Ok(&self.all - self.evaluate(a)?),
+            Filter::Condition { field, op, value } => {
+                let bitmaps = self
+                    .fields
+                    .get(field)
+                    .ok_or_else(|| format!("unknown filter attribute `{field}`"))?;
+
+                let matching = match bitmaps {
+                    FieldBitmaps::String(map) => {
+                        if !matches!(op, Operator::Eq | Operator::Ne) {
+                            return Err(format!("`{field}` is not an integer attribute"));
+                        }
+                        map.get(&value.to_lowercase()).cloned().unwrap_or_default()
+                    }
+                    FieldBitmaps::Integer(map) => {
+                        let value = value
+                            .parse::<i64>()
+                            .map_err(|_| format!("`{field}` needs an integer, got `{value}`"))?;
+                        let range = match op {
+                            Operator::Eq | Operator::Ne => {
+                                (Bound::Included(value), Bound::Included(value))
+                            }
+                            Operator::Gt => (Bound::Excluded(value), Bound::Unbounded),
+                            Operator::Ge => (Bound::Included(value), Bound::Unbounded),
+                            Operator::Lt => (Bound::Unbounded, Bound::Excluded(value)),
+                            Operator::Le => (Bound::Unbounded, Bound::Included(value)),
+                        };
+                        map.range(range).map(|(_, bitmap)| bitmap).fold(
+                            RoaringBitmap::new(),
+                            |mut acc, bitmap| {
+                                acc |= bitmap;
+                                acc
+                            },
+                        )
+                    }
+                };
+
+                match op {
+                    Operator::Ne => Ok(&self.all - matching),
+                    _ => Ok(matching),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::document::{AttributeValues, Attributes};
+
+    fn condition(field: &str, op: Operator, value: &str) -> Filter {
+        Filter::Condition {
+            field: field.to_string(),
+            op,
+            value: value.to_string(),
+        }
+    }
+
+    fn index() -> FilterIndex {
+        let docs: [&[(&str, &[&str])]; 5] = [
+            &[("year", &["1999"]), ("genre", &["Action", "Science Fiction"])],
+            &[("year", &["2001"]), ("genre", &["Comedy", "Romance"]), ("language", &["fr"])],
+            &[("year", &["2001"]), ("genre", &["Animation"]), ("language", &["ja"])],
+            &[("year", &["200"]), ("genre", &["Drama"]), ("language", &["en"])],
+            &[],
+        ];
+        let mut doc_map = DocumentMap::new();
+        for fields in docs {
+            let mut attributes = Attributes::default();
+            for (name, values) in fields {
+                let values = values.iter().map(|v| v.to_string()).collect();
+                attributes.fields.insert(name.to_string(), AttributeValues { values });
+            }
+            doc_map.add_doc(String::new(), 0, attributes);
+        }
+        doc_map.infer_schema();
+        FilterIndex::new(&doc_map)
+    }
+
+    fn ids(expr: &str) -> Vec<u32> {
+        let filter = parse_filter(expr).unwrap();
+        index().evaluate(&filter).unwrap().iter().collect()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_not_tighter_than_and() {
+        let (a, b, c) = (
+            condition("a", Operator::Eq, "1"),
+            condition("b", Operator::Eq, "2"),
+            condition("c", Operator::Eq, "3"),
+        );
+        let and = |x: &Filter, y: &Filter| Filter::And(Box::new(x.clone()), Box::new(y.clone()));
+        let or = |x: &Filter, y: &Filter| Filter::Or(Box::new(x.clone()), Box::new(y.clone()));
+        let not = |x: &Filter| Filter::Not(Box::new(x.clone()));
+
+        assert_eq!(parse_filter("a = 1 OR b = 2 AND c = 3").unwrap(), or(&a, &and(&b, &c)));
+        assert_eq!(parse_filter("a = 1 and b = 2 or c = 3").unwrap(), or(&and(&a, &b), &c));
+        assert_eq!(parse_filter("NOT a = 1 AND b = 2").unwrap(), and(&not(&a), &b));
+        assert_eq!(parse_filter("not not a = 1").unwrap(), not(&not(&a)));
+        assert_eq!(parse_filter("(a = 1 OR b = 2) AND c = 3").unwrap(), and(&or(&a, &b), &c));
+        assert_eq!(parse_filter("NOT (a = 1 OR b = 2)").unwrap(), not(&or(&a, &b)));
+    }
+
+    #[test]
+    fn operators_and_quoted_values() {
+        assert_eq!(parse_filter("a>=1").unwrap(), condition("a", Operator::Ge, "1"));
+        assert_eq!(parse_filter("a != 1").unwrap(), condition("a", Operator::Ne, "1"));
+        assert_eq!(parse_filter("a<1").unwrap(), condition("a", Operator::Lt, "1"));
+        assert_eq!(
+            parse_filter("genre = \"science fiction\"").unwrap(),
+            condition("genre", Operator::Eq, "science fiction")
+        );
+        // Quoted keywords are values, not operators.
+        assert_eq!(
+            parse_filter("genre = 'and' OR genre = \"it's\"").unwrap(),
+            Filter::Or(
+                Box::new(condition("genre", Operator::Eq, "and")),
+                Box::new(condition("genre", Operator::Eq, "it's")),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for expr in [
+            "",
+            "year",
+            "year >",
+            "year ! 2000",
+            "genre = \"drama",
+            "(year = 2000",
+            "year = 2000)",
+            "year = 2000 genre = drama",
+            "year = 2000 AND",
+            "AND year = 2000",
+        ] {
+            assert!(parse_filter(expr).is_err(), "{expr:?}");
+        }
+    }
+
+    #[test]
+    fn integers_compare_as_numbers() {
+        // As strings "200" would come after "1999".
+        assert_eq!(ids("year < 1999"), [3]);
+        assert_eq!(ids("year >= 1999"), [0, 1, 2]);
+        assert_eq!(ids("year > 1999 AND year <= 2001"), [1, 2]);
+        assert_eq!(ids("year = 02001"), [1, 2]);
+        // Like NOT, `!=` keeps the documents without the attribute.
+        assert_eq!(ids("year != 2001"), [0, 3, 4]);
+        assert!(index().evaluate(&parse_filter("year = new").unwrap()).is_err());
+    }
+
+    #[test]
+    fn strings_compare_case_insensitively() {
+        assert_eq!(ids("genre = drama"), [3]);
+        assert_eq!(ids("genre = 'SCIENCE FICTION'"), [0]);
+        assert!(index().evaluate(&parse_filter("genre > drama").unwrap()).is_err());
+        assert!(index().evaluate(&parse_filter("rating = 5").unwrap()).is_err());
+    }
+
+    #[test]
+    fn any_value_of_multi_valued_attributes_matches() {
+        assert_eq!(ids("genre = romance"), [1]);
+        assert_eq!(ids("genre = comedy AND genre = romance"), [1]);
+        // `!=` and NOT exclude documents with the value among others.
+        assert_eq!(ids("genre != comedy"), [0, 2, 3, 4]);
+        assert_eq!(ids("NOT genre = comedy"), [0, 2, 3, 4]);
+        assert_eq!(ids("NOT (genre = action OR language = fr) AND year = 2001"), [2]);
+    }
+}
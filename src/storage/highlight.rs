@@ -0,0 +1,408 @@
+use edit_distance::edit_distance;
+use serde::Serialize;
+
+use super::{document::Analyzer, record::QueryWords, trie::TypoPolicy};
+
+pub const DEFAULT_PRE_TAG: &str = "<span style='font-weight:bold;'>";
+pub const DEFAULT_POST_TAG: &str = "</span>";
+pub const DEFAULT_ALLOWED_TAGS: &str = "b,em,i,mark,span,strong";
+pub const DEFAULT_CROP_MARKER: &str = "…";
+
+/// Attributes a highlight tag may carry, everything else (notably event
+/// handlers, and `style` which could lay arbitrary CSS over the page) is
+/// rejected.
+const ALLOWED_ATTRIBUTES: [&str; 1] = ["class"];
+
+/// A query token matched against a word of the original document text.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MatchSpan {
+    /// Byte offsets into the original text.
+    pub start: usize,
+    pub end: usize,
+    /// Char offsets into the original text.
+    pub char_start: usize,
+    pub char_end: usize,
+    /// The query token this span was matched by.
+    pub token: String,
+    /// Edits needed to turn the token into the matched word.
+    pub typos: usize,
+}
+
+/// Matches every query token against the closest unused word of `text`,
+/// within the typo budget of `typos`, which should be the one the trie
+/// search used. Both sides are compared in their normalized form (see
+/// `Analyzer::tokenize`), spans point back at the original characters and
+/// are returned in text order.
+pub fn match_spans(
+    query: &str,
+    text: &str,
+    analyzer: Analyzer,
+    typos: &TypoPolicy,
+) -> Vec<MatchSpan> {
+    let words = analyzer.tokenize(text);
+    let mut used = vec![false; words.len()];
+    let mut spans = vec![];
+
+    for token in analyzer.tokenize(query) {
+        let token = token.text;
+        let best = words
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !used[*idx])
+            .map(|(idx, word)| (edit_distance(&token, &word.text), idx))
+            .min();
+
+        let (typos, idx) = match best {
+            Some(best) if best.0 <= typos.allowed_errors(&token) => best,
+            _ => continue,
+        };
+        used[idx] = true;
+
+        let word = &words[idx];
+        let char_start = text[..word.start].chars().count();
+        spans.push(MatchSpan {
+            start: word.start,
+            end: word.end,
+            char_start,
+            char_end: char_start + text[word.start..word.end].chars().count(),
+            token,
+            typos,
+        });
+    }
+
+    spans.sort_by_key(|span| span.start);
+    spans
+}
+
+/// Byte ranges of the whitespace separated words of `text`.
+fn word_ranges(text: &str) -> Vec<(usize, usize)> {
+    let mut ranges = vec![];
+    let mut start = None;
+
+    for (idx, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                ranges.push((s, idx));
+                start = None;
+            }
+            (false, None) => start = Some(idx),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        ranges.push((s, text.len()));
+    }
+
+    ranges
+}
+
+/// Cuts `text` down to a window of at most `nr_words` words around the best
+/// matching region and shifts `spans` onto the cropped text. `marker` is put
+/// in place of the words cut off at either end.
+///
+/// The region is built from the words `Record` scores proximity on, the
+/// earliest occurrences of every word of `query` (see
+/// `QueryWords::best_positions`), and from the words only matched with
+/// typos. The window covering the most of them wins, the earliest one on
+/// ties, and they are then centered inside it.
+pub fn crop(
+    text: &str,
+    spans: &[MatchSpan],
+    query: &QueryWords,
+    nr_words: usize,
+    marker: &str,
+) -> (String, Vec<MatchSpan>) {
+    let words = word_ranges(text);
+    if nr_words == 0 || words.len() <= nr_words {
+        return (text.to_owned(), spans.to_vec());
+    }
+    let word_of = |start: usize| words.partition_point(|(_, end)| *end <= start);
+
+    let tokens = query.analyzer().tokenize(text);
+    let mut matched = query
+        .best_positions(tokens.iter().map(|t| t.text.as_str()))
+        .into_iter()
+        .map(|pos| word_of(tokens[pos].start))
+        .collect::<Vec<usize>>();
+    matched.extend(spans.iter().filter(|s| s.typos > 0).map(|s| word_of(s.start)));
+
+    let mut best = (0, 0);
+    for start in 0..=words.len() - nr_words {
+        let count = matched
+            .iter()
+            .filter(|&&w| w >= start && w < start + nr_words)
+            .count();
+        if count > best.1 {
+            best = (start, count);
+        }
+    }
+
+    let mut first = best.0;
+    if best.1 > 0 {
+        let in_window = matched
+            .iter()
+            .filter(|&&w| w >= best.0 && w < best.0 + nr_words);
+        let lo = *in_window.clone().min().unwrap();
+        let hi = *in_window.max().unwrap();
+        let padding = (nr_words - (hi - lo + 1)) / 2;
+        first = lo.saturating_sub(padding).min(words.len() - nr_words);
+    }
+    let last = first + nr_words - 1;
+
+    let (start, end) = (words[first].0, words[last].1);
+    let mut cropped = String::new();
+    if first > 0 {
+        cropped.push_str(marker);
+    }
+    let shift = cropped.len();
+    let char_shift = cropped.chars().count();
+    let char_base = text[..start].chars().count();
+    cropped.push_str(&text[start..end]);
+    if last + 1 < words.len() {
+        cropped.push_str(marker);
+    }
+
+    let spans = spans
+        .iter()
+        .filter(|span| span.start >= start && span.end <= end)
+        .map(|span| MatchSpan {
+            start: span.start - start + shift,
+            end: span.end - start + shift,
+            char_start: span.char_start - char_base + char_shift,
+            char_end: span.char_end - char_base + char_shift,
+            ..span.clone()
+        })
+        .collect();
+
+    (cropped, spans)
+}
+
+/// Escapes text for use in HTML element content and quoted attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Element names clients may use as highlight tags.
+pub struct TagAllowlist {
+    names: Vec<String>,
+}
+
+impl TagAllowlist {
+    /// Builds the allowlist from a comma separated list of element names.
+    pub fn new(names: &str) -> Self {
+        TagAllowlist {
+            names: names
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Accepts `<name>` or `<name class='value'>` with an allowlisted name,
+    /// closed by `</name>`. Class names are letters, digits, `_` and `-`.
+    pub fn validate(&self, pre_tag: &str, post_tag: &str) -> Result<(), String> {
+        let invalid =
+            || Err(format!("highlight tags {pre_tag:?} / {post_tag:?} are not allowed"));
+
+        let inner = match pre_tag.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+            Some(inner) => inner,
+            None => return invalid(),
+        };
+        let (name, mut attributes) = match inner.split_once(char::is_whitespace) {
+            Some((name, attributes)) => (name.to_lowercase(), attributes.trim()),
+            None => (inner.to_lowercase(), ""),
+        };
+        if !self.names.contains(&name) {
+            return invalid();
+        }
+        if !post_tag.eq_ignore_ascii_case(&format!("</{name}>")) {
+            return invalid();
+        }
+
+        while !attributes.is_empty() {
+            let (attr, rest) = match attributes.split_once('=') {
+                Some(split) => split,
+                None => return invalid(),
+            };
+            if !ALLOWED_ATTRIBUTES.contains(&attr.trim().to_lowercase().as_str()) {
+                return invalid();
+            }
+
+            let rest = rest.trim_start();
+            let quote = match rest.chars().next() {
+                Some(q @ ('\'' | '"')) => q,
+                _ => return invalid(),
+            };
+            let (value, rest) = match rest[1..].split_once(quote) {
+                Some(split) => split,
+                None => return invalid(),
+            };
+            if !value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ' '))
+            {
+                return invalid();
+            }
+            attributes = rest.trim_start();
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders match spans as inline markup around the matched words. The
+/// document text is HTML escaped, the tags are inserted verbatim and should
+/// be checked with `TagAllowlist::validate` first.
+pub struct HighlightFormatter<'a> {
+    pub pre_tag: &'a str,
+    pub post_tag: &'a str,
+}
+
+impl<'a> Default for HighlightFormatter<'a> {
+    fn default() -> Self {
+        HighlightFormatter {
+            pre_tag: DEFAULT_PRE_TAG,
+            post_tag: DEFAULT_POST_TAG,
+        }
+    }
+}
+
+impl<'a> HighlightFormatter<'a> {
+    pub fn format(&self, text: &str, spans: &[MatchSpan]) -> String {
+        let mut formatted = String::with_capacity(text.len());
+        let mut last = 0;
+
+        for span in spans {
+            formatted.push_str(&escape_html(&text[last..span.start]));
+            formatted.push_str(self.pre_tag);
+            formatted.push_str(&escape_html(&text[span.start..span.end]));
+            formatted.push_str(self.post_tag);
+            last = span.end;
+        }
+        formatted.push_str(&escape_html(&text[last..]));
+
+        formatted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlight_tags_only_carry_class_names() {
+        let allowlist = TagAllowlist::new(DEFAULT_ALLOWED_TAGS);
+
+        assert!(allowlist.validate("<mark>", "</mark>").is_ok());
+        assert!(allowlist.validate("<span class='hit top-1'>", "</span>").is_ok());
+        assert!(allowlist.validate("<em class=\"a_b\">", "</EM>").is_ok());
+
+        assert!(allowlist.validate("<span style='position:fixed'>", "</span>").is_err());
+        assert!(allowlist.validate("<span class='a;b'>", "</span>").is_err());
+        assert!(allowlist.validate("<span class='x' onclick='y'>", "</span>").is_err());
+        assert!(allowlist.validate("<script>", "</script>").is_err());
+        assert!(allowlist.validate("<mark>", "</em>").is_err());
+    }
+
+    const TEXT: &str = "one two three four five six seven eight nine ten eleven twelve \
+                        thirteen fourteen fifteen";
+
+    fn crop_text(text: &str, query: &str) -> (String, Vec<MatchSpan>) {
+        let spans = match_spans(query, text, Analyzer::Standard, &TypoPolicy::default());
+        crop(text, &spans, &QueryWords::new(query, Analyzer::Standard), 5, "…")
+    }
+
+    fn crop_around(query: &str) -> (String, Vec<MatchSpan>) {
+        crop_text(TEXT, query)
+    }
+
+    #[test]
+    fn spans_follow_the_analyzer() {
+        let typos = TypoPolicy::default();
+        let text = "Amélie Poulain";
+
+        let spans = match_spans("amelie", text, Analyzer::Standard, &typos);
+        assert_eq!((spans[0].start, spans[0].end, spans[0].typos), (0, 7, 0));
+
+        // "amelie" is a typo of "amélie" once accents are kept.
+        let spans = match_spans("amelie", text, Analyzer::Accented, &typos);
+        assert_eq!(spans[0].typos, 1);
+        let spans = match_spans("amélie", text, Analyzer::Accented, &typos);
+        assert_eq!(spans[0].typos, 0);
+    }
+
+    #[test]
+    fn spans_follow_the_typo_policy() {
+        let text = "The Matrix";
+        let default = TypoPolicy::default();
+        let strict = TypoPolicy {
+            one_typo_min_len: usize::MAX,
+            two_typos_min_len: usize::MAX,
+        };
+
+        assert_eq!(match_spans("matrx", text, Analyzer::Standard, &default).len(), 1);
+        assert!(match_spans("matrx", text, Analyzer::Standard, &strict).is_empty());
+        assert_eq!(match_spans("matrix", text, Analyzer::Standard, &strict).len(), 1);
+    }
+
+    #[test]
+    fn crop_at_the_start() {
+        let (cropped, spans) = crop_around("one");
+        assert_eq!(cropped, "one two three four five…");
+        assert_eq!(&cropped[spans[0].start..spans[0].end], "one");
+    }
+
+    #[test]
+    fn crop_centers_matches_in_the_middle() {
+        let (cropped, spans) = crop_around("eight");
+        assert_eq!(cropped, "…six seven eight nine ten…");
+        assert_eq!(&cropped[spans[0].start..spans[0].end], "eight");
+        assert_eq!((spans[0].char_start, spans[0].char_end), (11, 16));
+    }
+
+    #[test]
+    fn crop_at_the_end() {
+        let (cropped, spans) = crop_around("fifteen");
+        assert_eq!(cropped, "…eleven twelve thirteen fourteen fifteen");
+        assert_eq!(&cropped[spans[0].start..spans[0].end], "fifteen");
+    }
+
+    #[test]
+    fn crop_picks_the_window_with_most_matches() {
+        let (cropped, spans) = crop_around("two twelve fourteen");
+        assert_eq!(cropped, "…eleven twelve thirteen fourteen fifteen");
+        assert_eq!(spans.len(), 2);
+    }
+
+    /// The window follows the positions proximity is scored on: the first
+    /// "star" counts, not the one next to "wars", and "Wars:" is a match.
+    #[test]
+    fn crop_follows_the_proximity_positions() {
+        let text = "Star one two three four five six seven eight star Wars: nine";
+        let (cropped, spans) = crop_text(text, "wars star");
+        assert_eq!(cropped, "Star one two three four…");
+        assert_eq!(spans.len(), 1);
+
+        let (cropped, _) = crop_text(text, "star star wars");
+        assert_eq!(cropped, "…seven eight star Wars: nine");
+    }
+
+    #[test]
+    fn crop_keeps_words_matched_with_typos() {
+        let (cropped, spans) = crop_around("twelv");
+        assert_eq!(cropped, "…ten eleven twelve thirteen fourteen…");
+        assert_eq!(spans[0].typos, 1);
+    }
+}
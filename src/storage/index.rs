@@ -0,0 +1,235 @@
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    distinct::DistinctIndex,
+    document::{Analyzer, Attributes, DocumentMap},
+    facet::{parse_facet_fields, FacetIndex},
+    feedback::FeedbackStore,
+    filter::FilterIndex,
+    record::{RankingRule, DEFAULT_RANKING_RULES},
+    sort::SortIndex,
+    successor::{SuccessorMap, SuccessorMapBuilder},
+    trie::{Trie, TypoPolicy},
+    word::WordMap,
+};
+
+pub const WORD_MAP_FNAME: &str = "word_map.proto.bin";
+pub const DOC_MAP_FNAME: &str = "doc_map.proto.bin";
+pub const SUCCESSORS_FNAME: &str = "successors.proto.bin";
+pub const SETTINGS_FNAME: &str = "settings.proto.bin";
+
+/// Settings chosen when an index is created, unset fields keep the
+/// defaults the default index is served with.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Message)]
+#[serde(default)]
+pub struct IndexSettings {
+    /// `standard` or `accented`, see `Analyzer`.
+    #[prost(string, tag = "1")]
+    pub analyzer: String,
+    #[prost(uint32, optional, tag = "2")]
+    pub one_typo_min_len: Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub two_typos_min_len: Option<u32>,
+    /// Relevance criteria in order, e.g. `["boost", "exactness"]`.
+    #[prost(string, repeated, tag = "4")]
+    pub ranking_rules: Vec<String>,
+    /// Facet fields, declared like `genre,year:10`.
+    #[prost(string, tag = "5")]
+    pub facets: String,
+}
+
+impl IndexSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        Analyzer::parse(&self.analyzer)?;
+        for rule in &self.ranking_rules {
+            RankingRule::parse(rule)?;
+        }
+        Ok(())
+    }
+
+    pub fn analyzer(&self) -> Analyzer {
+        Analyzer::parse(&self.analyzer).unwrap_or_default()
+    }
+
+    pub fn typo_policy(&self) -> TypoPolicy {
+        let default = TypoPolicy::default();
+        TypoPolicy {
+            one_typo_min_len: self
+                .one_typo_min_len
+                .map_or(default.one_typo_min_len, |len| len as usize),
+            two_typos_min_len: self
+                .two_typos_min_len
+                .map_or(default.two_typos_min_len, |len| len as usize),
+        }
+    }
+
+    pub fn ranking_rules(&self) -> Vec<RankingRule> {
+        let rules = self
+            .ranking_rules
+            .iter()
+            .filter_map(|rule| RankingRule::parse(rule).ok())
+            .collect::<Vec<RankingRule>>();
+        match rules.is_empty() {
+            true => DEFAULT_RANKING_RULES.to_vec(),
+            false => rules,
+        }
+    }
+}
+
+/// Builds the stored structures of an index from its documents.
+pub struct IndexBuilder {
+    analyzer: Analyzer,
+    word_map: WordMap,
+    doc_map: DocumentMap,
+}
+
+impl IndexBuilder {
+    pub fn new(analyzer: Analyzer) -> Self {
+        IndexBuilder {
+            analyzer,
+            word_map: WordMap::new(),
+            doc_map: DocumentMap::new(),
+        }
+    }
+
+    /// Starts from a copy of the documents of `index`, which keep their ids.
+    pub fn from_index(index: &Index) -> Self {
+        IndexBuilder {
+            analyzer: index.analyzer,
+            word_map: index.word_map.clone(),
+            doc_map: index.doc_map.clone(),
+        }
+    }
+
+    pub fn add_doc(&mut self, text: &str, boost: u32, attributes: Attributes) -> u32 {
+        let d = self.doc_map.add_doc(text.to_owned(), boost, attributes);
+        d.process(&mut self.word_map, self.analyzer);
+        d.id()
+    }
+
+    /// Replaces the document `doc_id`, false when there is none.
+    pub fn replace_doc(
+        &mut self,
+        doc_id: u32,
+        text: &str,
+        boost: u32,
+        attributes: Attributes,
+    ) -> bool {
+        if !self.remove_doc(doc_id) {
+            return false;
+        }
+        let d = self.doc_map.insert_doc(doc_id, text.to_owned(), boost, attributes);
+        d.process(&mut self.word_map, self.analyzer);
+        true
+    }
+
+    pub fn remove_doc(&mut self, doc_id: u32) -> bool {
+        if !self.doc_map.remove_doc(doc_id) {
+            return false;
+        }
+        self.word_map.remove_doc(doc_id);
+        true
+    }
+
+    /// An empty `facets` declaration keeps the facet fields the documents
+    /// already have, see `from_index`.
+    pub fn build(self, facets: &str) -> (WordMap, DocumentMap, SuccessorMap) {
+        let IndexBuilder {
+            analyzer,
+            word_map,
+            mut doc_map,
+        } = self;
+
+        doc_map.infer_schema();
+        if !facets.is_empty() {
+            doc_map.set_facet_fields(parse_facet_fields(facets));
+        }
+
+        let mut doc_ids = doc_map.doc_ids().collect::<Vec<u32>>();
+        doc_ids.sort();
+        let mut successors = SuccessorMapBuilder::new();
+        for doc_id in doc_ids {
+            if let Some(text) = doc_map.get_text(doc_id) {
+                successors.add_document(&analyzer.tokenize(text), doc_map.get_boost(doc_id));
+            }
+        }
+
+        (word_map, doc_map, successors.build())
+    }
+}
+
+/// Everything a search of one index reads, loaded from its stored
+/// structures.
+pub struct Index {
+    pub settings: IndexSettings,
+    pub analyzer: Analyzer,
+    pub ranking_rules: Vec<RankingRule>,
+    /// Scales boosts in `Record::score`.
+    pub max_boost: u32,
+    pub trie: Trie,
+    pub word_map: WordMap,
+    pub doc_map: DocumentMap,
+    pub successors: SuccessorMap,
+    /// Shared with the indexes rebuilt from this one, see `with_documents`.
+    pub feedback: Arc<RwLock<FeedbackStore>>,
+    pub filter_index: FilterIndex,
+    pub facet_index: FacetIndex,
+    pub sort_index: SortIndex,
+    pub distinct_index: DistinctIndex,
+}
+
+impl Index {
+    pub fn new(
+        settings: IndexSettings,
+        word_map: WordMap,
+        doc_map: DocumentMap,
+        successors: SuccessorMap,
+    ) -> Self {
+        let mut trie = Trie::new(word_map.word_hash.keys().collect());
+        trie.set_typo_policy(settings.typo_policy());
+
+        Index {
+            analyzer: settings.analyzer(),
+            ranking_rules: settings.ranking_rules(),
+            max_boost: doc_map.max_boost(),
+            settings,
+            trie,
+            // Derived from the documents on every load, never stored.
+            filter_index: FilterIndex::new(&doc_map),
+            facet_index: FacetIndex::new(&doc_map),
+            sort_index: SortIndex::new(&doc_map),
+            distinct_index: DistinctIndex::new(&doc_map),
+            word_map,
+            doc_map,
+            successors,
+            feedback: Arc::new(RwLock::new(FeedbackStore::new())),
+        }
+    }
+
+    /// An index of the documents of `builder` with the settings of this one.
+    /// Both share one feedback store, so clicks applied to this index while
+    /// the other is built are not lost once it replaces this one.
+    pub fn with_documents(&self, builder: IndexBuilder) -> Index {
+        let (word_map, doc_map, successors) = builder.build(&self.settings.facets);
+        let mut index = Index::new(self.settings.clone(), word_map, doc_map, successors);
+        index.feedback = self.feedback.clone();
+        index
+    }
+
+    /// Writes the stored structures into `dir`, next to each other like the
+    /// files of the default index.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(SETTINGS_FNAME), self.settings.encode_to_vec())?;
+        fs::write(dir.join(WORD_MAP_FNAME), self.word_map.encode_to_vec())?;
+        fs::write(dir.join(DOC_MAP_FNAME), self.doc_map.encode_to_vec())?;
+        fs::write(dir.join(SUCCESSORS_FNAME), self.successors.encode_to_vec())
+    }
+}
@@ -2,4 +2,14 @@ pub mod trie;
 pub mod document;
 pub mod word;
 pub mod cache;
-pub mod record;
\ No newline at end of file
+pub mod record;
+pub mod feedback;
+pub mod highlight;
+pub mod correction;
+pub mod completion;
+pub mod successor;
+pub mod filter;
+pub mod facet;
+pub mod sort;
+pub mod distinct;
+pub mod index;
\ No newline at end of file
@@ -1,231 +1,311 @@
-use std::{
-    borrow::BorrowMut,
-    collections::{BTreeMap, HashMap, HashSet},
-};
+use std::{cmp::Ordering, collections::HashMap};
+
+use super::document::Analyzer;
+use super::highlight::{match_spans, MatchSpan};
+use super::trie::TypoPolicy;
+
+/// A criterion of the relevance order, several are applied in turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankingRule {
+    /// More query words matched without typos.
+    Exactness,
+    /// Query words closer together and in query order.
+    Proximity,
+    /// Learned click-through popularity.
+    Popularity,
+    Boost,
+    /// Shorter documents.
+    Length,
+}
+
+pub const DEFAULT_RANKING_RULES: [RankingRule; 5] = [
+    RankingRule::Exactness,
+    RankingRule::Proximity,
+    RankingRule::Popularity,
+    RankingRule::Boost,
+    RankingRule::Length,
+];
+
+impl RankingRule {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "exactness" => Ok(RankingRule::Exactness),
+            "proximity" => Ok(RankingRule::Proximity),
+            "popularity" => Ok(RankingRule::Popularity),
+            "boost" => Ok(RankingRule::Boost),
+            "length" => Ok(RankingRule::Length),
+            _ => Err(format!("unknown ranking rule `{name}`")),
+        }
+    }
+}
+
+/// The words of a query as an index sees them, see `Analyzer::tokenize`.
+/// Built once per search and shared by its records.
+#[derive(Debug, Clone)]
+pub struct QueryWords {
+    text: String,
+    analyzer: Analyzer,
+    /// Distinct words in query order, with how often each occurs.
+    counts: Vec<(String, usize)>,
+    len: usize,
+}
+
+impl QueryWords {
+    pub fn new(query: &str, analyzer: Analyzer) -> Self {
+        let tokens = analyzer.tokenize(query);
+        let mut counts: Vec<(String, usize)> = vec![];
+        for token in &tokens {
+            match counts.iter_mut().find(|(word, _)| *word == token.text) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((token.text.to_owned(), 1)),
+            }
+        }
+
+        QueryWords {
+            text: query.to_owned(),
+            analyzer,
+            counts,
+            len: tokens.len(),
+        }
+    }
+
+    /// The query as given, e.g. for feedback lookups.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn analyzer(&self) -> Analyzer {
+        self.analyzer
+    }
+
+    /// Number of words, repeated ones included.
+    pub fn word_count(&self) -> usize {
+        self.len
+    }
 
-use difference::{Changeset, Difference};
+    /// Positions of `words` used for the proximity score: for every query
+    /// word, its first `n` occurrences, where `n` is how often the word
+    /// appears in the query (capped by how often it appears in `words`).
+    /// Positions come out sorted.
+    ///
+    /// The score `sum((p_i - i)^2)` over the sorted positions only grows when
+    /// a position moves later in the record, so taking the earliest
+    /// occurrences of every word is always a minimum. This replaces trying
+    /// every window combination, which is exponential in repeated words.
+    pub fn best_positions<'a>(&self, words: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+        let mut left = self.counts.iter().map(|(_, count)| *count).collect::<Vec<usize>>();
+        let mut positions = vec![];
+
+        for (pos, word) in words.into_iter().enumerate() {
+            if let Some(i) = self.counts.iter().position(|(w, _)| w == word) {
+                if left[i] > 0 {
+                    left[i] -= 1;
+                    positions.push(pos);
+                }
+            }
+        }
+
+        positions
+    }
+}
+
+/// The proximity score of sorted `positions`, 0 when the words come first
+/// and in order.
+pub fn distance(positions: &[usize]) -> f32 {
+    positions
+        .iter()
+        .enumerate()
+        .map(|(idx, &pos)| pos.abs_diff(idx).pow(2))
+        .sum::<usize>() as f32
+}
 
 #[derive(Debug)]
 pub struct Record {
+    pub doc_id: u32,
     pub record: String,
     query: String,
-    query_pos: HashMap<String, Vec<usize>>,
-    record_pos: Vec<(String, Vec<usize>)>,
+    query_words: usize,
     pub positions: HashMap<String, Vec<usize>>,
     pub exact_matches: usize,
     pub operations: usize,
     pub similarity: f32,
-    pub updated_record: String,
+    pub boost: u32,
+    /// Decayed click-through popularity learned from `/feedback`.
+    pub popularity: f64,
 }
 
 impl Record {
-    pub fn new(
-        query: &str,
-        query_pos: &HashMap<String, Vec<usize>>,
-        record: &str,
-        exact_matches: usize,
-        operations: usize,
-    ) -> Self {
-        let mut record_pos_mp = HashMap::new();
-        for (idx, wi) in record.to_lowercase().split_whitespace().enumerate() {
-            record_pos_mp
-                .entry(wi.to_string())
-                .or_insert(vec![])
-                .push(idx);
-        }
-
-        let mut record_pos = Vec::new();
-        let mut tmp_record_pos = HashSet::new();
-        for wi in query.to_lowercase().split_whitespace() {
-            if let Some(_) = tmp_record_pos.get(wi) {
-                continue;
-            }
-            tmp_record_pos.insert(wi);
-            if let Some(v) = record_pos_mp.get(wi) {
-                record_pos.push((wi.to_owned(), v.to_owned()));
-            }
-        }
-
-        println!("query_pos: {query_pos:?}\nrecord_pos: {record_pos:?}");
-
-        //record_pos.iter().map(|(k, v)| (k.to_owned(), (v.len(), v.to_owned()))).collect::<HashMap<String, (usize, Vec<usize>)>>();
-
-        let mut r = Record {
+    /// Scores the proximity of `query` in `record`, tokenized with the
+    /// analyzer of the query, so "Star:" counts as "star".
+    pub fn new(query: &QueryWords, record: &str, exact_matches: usize, operations: usize) -> Self {
+        let tokens = query.analyzer.tokenize(record);
+        let positions = query.best_positions(tokens.iter().map(|t| t.text.as_str()));
+
+        Record {
+            doc_id: 0,
             record: record.to_string(),
-            query: query.to_owned(),
-            query_pos: query_pos.clone(),
-            record_pos,
+            query: query.text.to_owned(),
+            query_words: query.word_count(),
             positions: HashMap::new(),
             exact_matches,
             operations,
-            similarity: 0.0,
-            updated_record: record.to_owned(),
-        };
-
-        r.similarity = r._calculate_distance();
-        // println!("--- {record}  {}", r.similarity);
-        r
-    }
-
-    fn generate_combinations(
-        &self,
-        current: &mut Vec<usize>,
-        index: usize,
-        result: &mut Vec<Vec<usize>>,
-    ) {
-        // println!("index -- {index}, data == {:?}", self.record_pos[index]);
-
-        if index == self.record_pos.len() {
-            current.sort();
-            result.push(current.to_vec());
-            return;
+            similarity: distance(&positions),
+            boost: 0,
+            popularity: 0.0,
         }
+    }
 
-        let wi = &self.record_pos[index].0;
-        let step = self.query_pos.get(wi).unwrap_or(&Vec::new()).len();
-
-        let word_positions = &self.record_pos[index].1;
-
-        let step = std::cmp::min(step, word_positions.len());
+    pub fn calculate_distance(&self) -> f32 {
+        self.similarity as f32
+    }
 
-        // println!("wi: {wi}\tstpe: {step}");
+    /// Total ranking order: more exact matches, then tighter positional
+    /// distance, then learned popularity, boost, shorter title and finally
+    /// the document id, so equal records never depend on iteration order.
+    pub fn rank_cmp(&self, other: &Self) -> Ordering {
+        self.rank_by(other, &DEFAULT_RANKING_RULES)
+    }
 
-        for item in word_positions.windows(step) {
-            let mut new_current = current.clone();
-            new_current.extend(item);
-            println!("idx: {index} {wi}  new_current: {new_current:?}");
+    /// Like `rank_cmp` with the criteria of an index's own `rules`; the
+    /// document id still breaks the remaining ties.
+    pub fn rank_by(&self, other: &Self, rules: &[RankingRule]) -> Ordering {
+        self.relevance_cmp(other, rules)
+            .then_with(|| self.doc_id.cmp(&other.doc_id))
+    }
 
-            self.generate_combinations(&mut new_current, index + 1, result);
+    /// The order of `rules` alone, `Equal` when none of them decides, so
+    /// that other criteria can follow, see `SortIndex::compare`.
+    pub fn relevance_cmp(&self, other: &Self, rules: &[RankingRule]) -> Ordering {
+        for rule in rules {
+            let ordering = match rule {
+                RankingRule::Exactness => other.exact_matches.cmp(&self.exact_matches),
+                RankingRule::Proximity => self
+                    .calculate_distance()
+                    .total_cmp(&other.calculate_distance()),
+                RankingRule::Popularity => other.popularity.total_cmp(&self.popularity),
+                RankingRule::Boost => other.boost.cmp(&self.boost),
+                RankingRule::Length => {
+                    self.record.chars().count().cmp(&other.record.chars().count())
+                }
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
         }
+        Ordering::Equal
     }
 
-    pub fn generate_all_combinations(&self) -> Vec<Vec<usize>> {
-        let mut result = Vec::new();
-
-        // println!("record_pos len: {}", self.record_pos.len());
-
-        // let mut itr: std::collections::hash_map::Iter<'_, String, (usize, Vec<usize)>> = self.query_pos.iter();
-        // println!("pos: {:?} \nitr: {:?}", self.query_pos, itr.next());
+    /// Relevance on a 0 to 1 scale that doesn't depend on the index, so the
+    /// results of several indexes can be merged. `max_boost` is the largest
+    /// boost in the record's index.
+    pub fn score(&self, max_boost: u32) -> f64 {
+        let query_words = self.query_words.max(1) as f64;
+        let record_words = self.record.split_whitespace().count().max(1) as f64;
+
+        let exactness = (self.exact_matches as f64 / query_words).min(1.0);
+        let typos = 1.0 / (1.0 + self.operations as f64);
+        let proximity = 1.0 / (1.0 + self.calculate_distance() as f64);
+        let coverage = (query_words / record_words).min(1.0);
+        let boost = match max_boost {
+            0 => 0.0,
+            max => (1.0 + self.boost as f64).ln() / (1.0 + max as f64).ln(),
+        };
 
-        self.generate_combinations(&mut Vec::new(), 0, &mut result);
-        result
+        0.35 * exactness + 0.2 * typos + 0.15 * proximity + 0.1 * coverage + 0.2 * boost
     }
 
-    fn _calculate_distance(&mut self) -> f32 {
-        let positons = self.generate_all_combinations();
-
-        // println!("#### positons: {positons:?}");
-
-        // let min_distance = positons
-        //     .iter()
-        //     .map(|position| {
-        //         let first = position.first().unwrap_or(&0);
-        //         let tmp = position
-        //             .iter()
-        //             // .map(|e| e - first)
-        //             .zip(0..position.len())
-        //             .map(|(query_pos, record_pos)| (query_pos - record_pos).pow(2))
-        //             .sum::<usize>();
-        //         // println!("position: {position:?}   tmp --- {tmp}");
-        //         tmp
-        //     })
-        //     .min()
-        //     .unwrap_or(usize::MAX);
-
-        let mut mn_dist = usize::MAX;
-
-        for position in positons {
-            let first = position.first().unwrap_or(&0);
-            let dist = position
-                .iter()
-                // .map(|e| e - first)
-                .zip(0..position.len())
-                .map(|(query_pos, record_pos)| (query_pos - record_pos).pow(2))
-                .sum::<usize>();
+    /// Where the query tokens matched in the original record text, with the
+    /// analyzer of the index and the typo policy of the search.
+    pub fn match_spans(&self, analyzer: Analyzer, typos: &TypoPolicy) -> Vec<MatchSpan> {
+        match_spans(&self.query, &self.record, analyzer, typos)
+    }
+}
 
-            if dist < mn_dist {
-                mn_dist = dist;
-                self.updated_record = self.highlight_changes(&position);
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        mn_dist as f32
+    fn proximity(query: &str, record: &str) -> f32 {
+        let query = QueryWords::new(query, Analyzer::Standard);
+        Record::new(&query, record, 0, 0).calculate_distance()
     }
 
-    pub fn calculate_distance(&self) -> f32 {
-        self.similarity as f32
+    #[test]
+    fn repeated_record_words_use_the_earliest_occurrences() {
+        let record = "The Man Who Knew Too Much The Man";
+        assert_eq!(proximity("the man", record), 0.0);
+        assert_eq!(proximity("man who", record), 2.0);
+        assert_eq!(proximity("much the man", record), 9.0);
     }
 
-    fn highlight_changes(&self, position: &Vec<usize>) -> String {
-        let updated_record = self
-            .record
-            .to_owned()
-            .split_ascii_whitespace()
-            .collect::<Vec<&str>>();
+    #[test]
+    fn repeated_query_words_take_one_occurrence_each() {
+        let record = "The Man Who Knew Too Much The Man";
+        // "the" twice: positions 0 and 6, "man" once: position 1.
+        assert_eq!(proximity("the man the", record), 16.0);
+        // More repetitions than the record has are capped by the record.
+        assert_eq!(proximity("man man man", record), 37.0);
+        assert_eq!(proximity("the the the", "The End"), 0.0);
+    }
 
-        let mut idx = 0;
+    #[test]
+    fn missing_words_are_left_out() {
+        assert_eq!(proximity("lord rings", "The Lord of the Flies"), 1.0);
+        assert_eq!(proximity("rings", "The Lord of the Flies"), 0.0);
+    }
 
-        let query_vec = self.query.split_ascii_whitespace().collect::<Vec<&str>>();
+    #[test]
+    fn single_word() {
+        assert_eq!(proximity("man", "The Man"), 1.0);
+        assert_eq!(proximity("the", "The Man"), 0.0);
+        // A record word before its query position must not underflow.
+        assert_eq!(proximity("a b c man", "Man"), 0.0);
+    }
 
-        let mut record_vec = self.record.split_ascii_whitespace().map(|s| s.to_owned()).collect::<Vec<String>>();
+    /// Words are compared as the index stores them, not as they are
+    /// written.
+    #[test]
+    fn punctuation_and_accents_are_normalized() {
+        assert_eq!(proximity("star", "Star: The Legend"), 0.0);
+        assert_eq!(proximity("star", "Porn Star: The Legend of Ron Jeremy"), 1.0);
+        assert_eq!(proximity("star", "Fist of the North Star: Legends"), 16.0);
+        assert_eq!(proximity("star wars", "Star Wars: Episode I"), 0.0);
+        assert_eq!(proximity("amélie", "Amélie"), 0.0);
+        assert_eq!(proximity("AMELIE", "The Fabulous Amélie Poulain"), 4.0);
+    }
 
-        for (idx, &pos) in position.iter().enumerate() {
-            if idx >= query_vec.len() {
-                break;
-            }
+    fn scored(query: &str, record: &str, exact: usize, ops: usize, boost: u32) -> Record {
+        let query = QueryWords::new(query, Analyzer::Standard);
+        let mut r = Record::new(&query, record, exact, ops);
+        r.boost = boost;
+        r
+    }
 
-            let wi = self.highlight_word(query_vec[idx], &record_vec[pos]);
-            record_vec[pos] = wi;   
-        }
+    #[test]
+    fn exact_match_scores_above_typo() {
+        let exact = scored("matrix", "The Matrix", 1, 0, 10);
+        let typo = scored("matrx", "The Matrix", 0, 1, 10);
+        assert!(exact.score(100) > typo.score(100));
+        // Even a much less popular exact match.
+        let exact = scored("matrix", "The Matrix", 1, 0, 0);
+        assert!(exact.score(100) > typo.score(100));
+    }
 
-        record_vec.join(" ")
-    }
-
-    fn highlight_word(&self, original: &str, corrected: &str) -> String {
-        let original_lower = original.to_lowercase();
-        let corrected_lower = corrected.to_lowercase();
-    
-        let changeset = Changeset::new(&original_lower, &corrected_lower, "");
-    
-        let mut highlighted = String::new();
-        let mut orig_iter = original.chars();
-    
-        for diff in changeset.diffs {
-            match diff {
-                Difference::Same(s) => highlighted.push_str(&format!("<span style='font-weight:bold;'>{}</span>", s)),
-                Difference::Add(s) => {
-                    highlighted.push_str(&format!("<span style='font-weight:lighter;'>{}</span>", s));
-                }
-                Difference::Rem(s) => {
-                    if let Some(orig_char) = orig_iter.next() {
-                        highlighted.push_str(&format!("<span style='font-weight:bold;'>{}</span>", orig_char));
-                    }
-                }
+    #[test]
+    fn score_is_bounded() {
+        let cases = [
+            ("matrix", "The Matrix", 1, 0),
+            ("matrx", "The Matrix", 0, 1),
+            ("the man the", "The Man Who Knew Too Much The Man", 3, 0),
+            ("lord rings", "The Lord of the Flies", 1, 2),
+            ("a b c man", "Man", 1, 1000),
+            ("", "", 0, 0),
+        ];
+        for (query, record, exact, ops) in cases {
+            for (boost, max_boost) in [(0, 0), (0, 100), (100, 100), (u32::MAX, u32::MAX)] {
+                let score = scored(query, record, exact, ops, boost).score(max_boost);
+                assert!((0.0..=1.0).contains(&score), "{query:?} {record:?}: {score}");
             }
         }
-    
-        highlighted
-    }
-    
-
-    // fn highlight_word(&self, original: &str, suggested: &str) -> String {
-    //     let original_chars: Vec<_> = original.chars().collect();
-    //     let suggested_chars: Vec<_> = suggested.chars().collect();
-    
-    //     let mut highlighted = String::new();
-    
-    //     for (orig_char, sug_char) in original_chars.iter().zip(suggested_chars.iter()) {
-    //         if orig_char.to_lowercase().next() != sug_char.to_lowercase().next() {
-    //             // Highlight the differing characters
-    //             highlighted.push_str(&format!("<span class='highlight'>{}</span>", sug_char));
-    //         } else {
-    //             highlighted.push(*orig_char);
-    //         }
-    //     }
-    
-    //     // Append any remaining characters from the suggested word
-    //     // highlighted.push_str(&suggested_chars[original_chars.len()..].iter().collect::<String>());
-    
-    //     highlighted
-    // }
+    }
 }
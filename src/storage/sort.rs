@@ -0,0 +1,245 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use super::{
+    document::{set_doc_value, AttributeKind, DocumentMap},
+    record::{RankingRule, Record},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SortRule {
+    /// The ranking rules of the index, see `Record::relevance_cmp`.
+    Relevance,
+    /// The document text, case insensitive.
+    Title(Direction),
+    Attribute(String, Direction),
+}
+
+/// Parses `year:desc,title:asc`. The direction defaults to ascending, and
+/// relevance breaks remaining ties unless it is listed explicitly.
+pub fn parse_sort(spec: &str) -> Result<Vec<SortRule>, String> {
+    let mut rules = vec![];
+
+    for rule in spec.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (field, direction) = match rule.split_once(':') {
+            Some((field, "asc")) => (field, Direction::Asc),
+            Some((field, "desc")) => (field, Direction::Desc),
+            Some((_, direction)) => return Err(format!("unknown sort direction `{direction}`")),
+            None => (rule, Direction::Asc),
+        };
+
+        rules.push(match field {
+            "relevance" => SortRule::Relevance,
+            "title" => SortRule::Title(direction),
+            _ => SortRule::Attribute(field.to_owned(), direction),
+        });
+    }
+
+    if !rules.contains(&SortRule::Relevance) {
+        rules.push(SortRule::Relevance);
+    }
+    Ok(rules)
+}
+
+/// Smallest and largest value of a document, multi valued attributes sort
+/// by the former ascending and by the latter descending.
+enum SortColumn {
+    Integer(Vec<Option<(i64, i64)>>),
+    String(Vec<Option<(String, String)>>),
+}
+
+fn min_max<T: Ord + Clone>(values: impl Iterator<Item = T>) -> Option<(T, T)> {
+    values.fold(None, |acc, v| match acc {
+        None => Some((v.clone(), v)),
+        Some((lo, hi)) => Some((lo.min(v.clone()), hi.max(v))),
+    })
+}
+
+fn cmp_column<T: Ord>(a: Option<&(T, T)>, b: Option<&(T, T)>, direction: Direction) -> Ordering {
+    match (a, b, direction) {
+        (Some(a), Some(b), Direction::Asc) => a.0.cmp(&b.0),
+        (Some(a), Some(b), Direction::Desc) => b.1.cmp(&a.1),
+        // Documents without the attribute go last either way.
+        (Some(_), None, _) => Ordering::Less,
+        (None, Some(_), _) => Ordering::Greater,
+        (None, None, _) => Ordering::Equal,
+    }
+}
+
+/// Sort values of every attribute, looked up by document id while
+/// comparing.
+pub struct SortIndex {
+    columns: HashMap<String, SortColumn>,
+}
+
+impl SortIndex {
+    pub fn new(doc_map: &DocumentMap) -> Self {
+        let mut columns = HashMap::new();
+
+        for (doc_id, attributes) in doc_map.attributes() {
+            for (name, values) in &attributes.fields {
+                let column = columns.entry(name.to_owned()).or_insert_with(|| {
+                    match doc_map.attribute_kind(name) {
+                        Some(AttributeKind::Integer) => SortColumn::Integer(vec![]),
+                        _ => SortColumn::String(vec![]),
+                    }
+                });
+
+                match column {
+                    SortColumn::Integer(docs) => {
+                        let parsed = values.values.iter().filter_map(|v| v.trim().parse().ok());
+                        set_doc_value(docs, doc_id, min_max(parsed));
+                    }
+                    SortColumn::String(docs) => {
+                        let lowered = values.values.iter().map(|v| v.to_lowercase());
+                        set_doc_value(docs, doc_id, min_max(lowered));
+                    }
+                }
+            }
+        }
+
+        SortIndex { columns }
+    }
+
+    /// Checks that every attribute in `rules` exists.
+    pub fn validate(&self, rules: &[SortRule]) -> Result<(), String> {
+        for rule in rules {
+            if let SortRule::Attribute(field, _) = rule {
+                if !self.columns.contains_key(field) {
+                    return Err(format!("unknown sort attribute `{field}`"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Orders two records by `rules` in turn, then by document id.
+    pub fn compare(
+        &self,
+        rules: &[SortRule],
+        ranking: &[RankingRule],
+        a: &Record,
+        b: &Record,
+    ) -> Ordering {
+        for rule in rules {
+            let ordering = match rule {
+                SortRule::Relevance => a.relevance_cmp(b, ranking),
+                SortRule::Title(direction) => {
+                    let ordering = a
+                        .record
+                        .chars()
+                        .flat_map(char::to_lowercase)
+                        .cmp(b.record.chars().flat_map(char::to_lowercase));
+                    match direction {
+                        Direction::Asc => ordering,
+                        Direction::Desc => ordering.reverse(),
+                    }
+                }
+                SortRule::Attribute(field, direction) => {
+                    let (a, b) = (a.doc_id as usize, b.doc_id as usize);
+                    match self.columns.get(field) {
+                        Some(SortColumn::Integer(docs)) => cmp_column(
+                            docs.get(a).and_then(Option::as_ref),
+                            docs.get(b).and_then(Option::as_ref),
+                            *direction,
+                        ),
+                        Some(SortColumn::String(docs)) => cmp_column(
+                            docs.get(a).and_then(Option::as_ref),
+                            docs.get(b).and_then(Option::as_ref),
+                            *direction,
+                        ),
+                        None => Ordering::Equal,
+                    }
+                }
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        a.doc_id.cmp(&b.doc_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::document::{Analyzer, AttributeValues, Attributes};
+    use crate::storage::record::{QueryWords, DEFAULT_RANKING_RULES};
+
+    #[test]
+    fn parse_defaults_to_ascending_and_relevance_last() {
+        assert_eq!(
+            parse_sort("year:desc, title").unwrap(),
+            [
+                SortRule::Attribute("year".to_string(), Direction::Desc),
+                SortRule::Title(Direction::Asc),
+                SortRule::Relevance,
+            ]
+        );
+        assert_eq!(
+            parse_sort("relevance,year:desc").unwrap(),
+            [
+                SortRule::Relevance,
+                SortRule::Attribute("year".to_string(), Direction::Desc),
+            ]
+        );
+        assert_eq!(parse_sort("").unwrap(), [SortRule::Relevance]);
+        assert!(parse_sort("year:down").is_err());
+    }
+
+    /// Equally relevant titles of different years, the newest one with the
+    /// highest id.
+    fn index_and_records() -> (SortIndex, Vec<Record>) {
+        let mut doc_map = DocumentMap::new();
+        for year in ["1999", "2003", "2021"] {
+            let mut attributes = Attributes::default();
+            let values = vec![year.to_string()];
+            attributes.fields.insert("year".to_string(), AttributeValues { values });
+            doc_map.add_doc("The Matrix".to_string(), 0, attributes);
+        }
+        doc_map.infer_schema();
+
+        let query = QueryWords::new("matrix", Analyzer::Standard);
+        let records = (0..3)
+            .map(|doc_id| {
+                let mut record = Record::new(&query, "The Matrix", 1, 0);
+                record.doc_id = doc_id;
+                record
+            })
+            .collect();
+        (SortIndex::new(&doc_map), records)
+    }
+
+    fn sorted_ids(spec: &str) -> Vec<u32> {
+        let (index, mut records) = index_and_records();
+        let rules = parse_sort(spec).unwrap();
+        index.validate(&rules).unwrap();
+        records.sort_by(|a, b| index.compare(&rules, &DEFAULT_RANKING_RULES, a, b));
+        records.iter().map(|r| r.doc_id).collect()
+    }
+
+    #[test]
+    fn rules_after_relevance_break_its_ties() {
+        assert_eq!(sorted_ids("relevance,year:desc"), [2, 1, 0]);
+        assert_eq!(sorted_ids("relevance,year:asc"), [0, 1, 2]);
+        // The document id only comes last.
+        assert_eq!(sorted_ids("relevance"), [0, 1, 2]);
+        assert_eq!(sorted_ids("year:desc"), [2, 1, 0]);
+    }
+
+    #[test]
+    fn relevance_comes_before_the_rules_after_it() {
+        let (index, mut records) = index_and_records();
+        records[0].exact_matches = 2;
+        let rules = parse_sort("relevance,year:desc").unwrap();
+        records.sort_by(|a, b| index.compare(&rules, &DEFAULT_RANKING_RULES, a, b));
+        assert_eq!(records.iter().map(|r| r.doc_id).collect::<Vec<u32>>(), [0, 2, 1]);
+    }
+}
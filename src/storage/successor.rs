@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use super::document::Token;
+
+/// Successors kept per context, the long tail is rarely worth proposing.
+const MAX_SUCCESSORS: usize = 10;
+/// A two word prediction like "is born" is only made when the second word
+/// follows the first at least this often, and in more than a single
+/// unpopular document.
+const PHRASE_SHARE: f64 = 0.5;
+const MIN_PHRASE_WEIGHT: f64 = 2.0;
+
+#[derive(PartialEq, Serialize, Deserialize, Message)]
+pub struct NextWord {
+    #[prost(string, tag = "1")]
+    pub word: String,
+    #[prost(double, tag = "2")]
+    pub weight: f64,
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Message)]
+pub struct NextWords {
+    #[prost(message, repeated, tag = "1")]
+    pub words: Vec<NextWord>,
+}
+
+/// Words following one ("star") or two ("star wars") indexed words, heaviest
+/// first, weighted by the popularity of the documents they appear in.
+#[derive(Serialize, Deserialize, Message)]
+pub struct SuccessorMap {
+    #[prost(map = "string, message", tag = "1")]
+    successors: HashMap<String, NextWords>,
+}
+
+impl SuccessorMap {
+    pub fn new() -> Self {
+        SuccessorMap {
+            successors: HashMap::new(),
+        }
+    }
+
+    fn next_words(&self, context: &[&str]) -> &[NextWord] {
+        self.successors
+            .get(&context.join(" "))
+            .map_or(&[], |next| &next.words)
+    }
+
+    /// Likely continuations of `words`, predicted from the last two words
+    /// when that context was seen, otherwise from the last word.
+    pub fn predict(&self, words: &[&str], limit: usize) -> Vec<String> {
+        let last = match words.last() {
+            Some(last) => *last,
+            None => return vec![],
+        };
+
+        let mut next = &[][..];
+        if words.len() >= 2 {
+            next = self.next_words(&words[words.len() - 2..]);
+        }
+        if next.is_empty() {
+            next = self.next_words(&[last]);
+        }
+
+        next.iter()
+            .take(limit)
+            .map(|first| {
+                let following = self.next_words(&[last, &first.word]);
+                let total = following.iter().map(|w| w.weight).sum::<f64>();
+                match following.first() {
+                    Some(second)
+                        if second.weight >= PHRASE_SHARE * total
+                            && second.weight >= MIN_PHRASE_WEIGHT =>
+                    {
+                        format!("{} {}", first.word, second.word)
+                    }
+                    _ => first.word.to_owned(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Accumulates bigram and trigram successor weights while indexing.
+#[derive(Default)]
+pub struct SuccessorMapBuilder {
+    weights: HashMap<String, HashMap<String, f64>>,
+}
+
+impl SuccessorMapBuilder {
+    pub fn new() -> Self {
+        SuccessorMapBuilder::default()
+    }
+
+    pub fn add_document(&mut self, tokens: &[Token], boost: u32) {
+        let weight = 1.0 + (1.0 + boost as f64).ln();
+
+        for (pos, token) in tokens.iter().enumerate().skip(1) {
+            let mut contexts = vec![tokens[pos - 1].text.to_owned()];
+            if pos >= 2 {
+                contexts.push(format!("{} {}", tokens[pos - 2].text, tokens[pos - 1].text));
+            }
+
+            for context in contexts {
+                *self
+                    .weights
+                    .entry(context)
+                    .or_default()
+                    .entry(token.text.to_owned())
+                    .or_insert(0.0) += weight;
+            }
+        }
+    }
+
+    pub fn build(self) -> SuccessorMap {
+        let successors = self
+            .weights
+            .into_iter()
+            .map(|(context, next)| {
+                let mut words = next
+                    .into_iter()
+                    .map(|(word, weight)| NextWord { word, weight })
+                    .collect::<Vec<NextWord>>();
+                words.sort_by(|a, b| {
+                    b.weight
+                        .total_cmp(&a.weight)
+                        .then_with(|| a.word.cmp(&b.word))
+                });
+                words.truncate(MAX_SUCCESSORS);
+                (context, NextWords { words })
+            })
+            .collect();
+
+        SuccessorMap { successors }
+    }
+}
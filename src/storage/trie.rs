@@ -1,11 +1,15 @@
 use edit_distance::edit_distance;
 use fst::{automaton::Levenshtein, IntoStreamer, Set};
 use priority_queue::PriorityQueue;
+use roaring::RoaringBitmap;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::{min, Ordering},
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
 };
 
 use crate::storage::cache;
@@ -57,10 +61,158 @@ impl<'a> PartialOrd for QueryWalker<'a> {
     }
 }
 
+/// Typos tolerated when matching a query word against the vocabulary.
+pub fn allowed_errors(w: &str) -> usize {
+    TypoPolicy::default().allowed_errors(w)
+}
+
+/// Word lengths from which one and two typos are tolerated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TypoPolicy {
+    pub one_typo_min_len: usize,
+    pub two_typos_min_len: usize,
+}
+
+impl Default for TypoPolicy {
+    fn default() -> Self {
+        TypoPolicy {
+            one_typo_min_len: 1,
+            two_typos_min_len: 5,
+        }
+    }
+}
+
+impl TypoPolicy {
+    pub fn allowed_errors(&self, w: &str) -> usize {
+        if w.len() >= self.two_typos_min_len {
+            2
+        } else if w.len() >= self.one_typo_min_len {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// Tells tries apart in the shared similar words cache.
+static TRIE_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// `(exact matches, edits)` of matching documents by id, shared between
+/// searches.
+pub type MatchedDocs = Arc<HashMap<u32, (usize, usize)>>;
+
+/// Matches of the first words of a query, narrowed one word at a time by
+/// `Trie::extend_matches`. Steps are shared through `Arc`s by the searches
+/// starting with the same words, see `Trie::find_matches`.
+#[derive(Debug, Default)]
+pub struct PartialMatches {
+    /// Documents matching every word so far, `None` before the first word.
+    ids: Option<RoaringBitmap>,
+    /// `(exact matches, edits)` of the documents of `ids`.
+    docs: MatchedDocs,
+    /// `(doc id, position)` of the postings of `ids` matched so far. Left
+    /// empty by the last word of a query, whose step is never extended.
+    used: HashSet<(u32, u32)>,
+}
+
+/// What a step of `PartialMatches` is shared under: the words it matched
+/// and whether the last of them was matched as the last query word.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct MatchesKey {
+    trie: u32,
+    typos: TypoPolicy,
+    words: Vec<String>,
+    is_last: bool,
+}
+
+/// A step of `PartialMatches` with the key it is shared under.
+type SharedMatches = (MatchesKey, Arc<PartialMatches>);
+
+/// What the searches of a type-ahead session keep for the next one.
+#[derive(Debug, Default)]
+pub struct SessionMatches {
+    /// Set to stop the running search between two words.
+    pub cancel: AtomicBool,
+    /// Matches of the words before the last one of the latest query, which
+    /// the next keystroke usually builds on. Kept here as well as in the
+    /// shared cache, where it may have been evicted by other searches.
+    prefix: Mutex<Option<SharedMatches>>,
+}
+
+impl SessionMatches {
+    fn prefix(&self, key: &MatchesKey) -> Option<Arc<PartialMatches>> {
+        match &*self.prefix.lock().unwrap() {
+            Some((prefix_key, matches)) if prefix_key == key => Some(matches.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Why matching a query stopped before it was done.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MatchError {
+    /// Stopped by its caller, see `SessionMatches::cancel`.
+    Cancelled,
+    /// A query word too long to build its typo automaton.
+    WordTooLong(String),
+}
+
+/// Fails once the search of `session` is cancelled.
+fn check_cancel(session: Option<&SessionMatches>) -> Result<(), MatchError> {
+    match session.is_some_and(|s| s.cancel.load(AtomicOrdering::Relaxed)) {
+        true => Err(MatchError::Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// Narrows `docs` down to the exact matches of the single query `word`
+/// that can still rank in the top `k`: the `k` whose first occurrence of
+/// the word comes earliest, and any others at that same position. With the
+/// default ranking rules exactness comes first and proximity, for one word
+/// the square of that position, right after it, so no document left out
+/// could outrank them. `docs` is returned as is when it has `k` exact
+/// matches or fewer.
+fn closest_exact(docs: &MatchedDocs, word: &str, word_map: &WordMap, k: usize) -> MatchedDocs {
+    let exact = docs.values().filter(|(exact, _)| *exact > 0).count();
+    if exact <= k || k == 0 {
+        return docs.clone();
+    }
+    let Some(word) = word_map.word_hash.get(word) else {
+        return docs.clone();
+    };
+
+    let mut first_pos = HashMap::new();
+    for rec in &word.in_records {
+        if docs.get(&rec.idx).is_some_and(|(exact, _)| *exact > 0) {
+            let pos = first_pos.entry(rec.idx).or_insert(rec.pos);
+            *pos = min(*pos, rec.pos);
+        }
+    }
+    let mut positions = first_pos.values().copied().collect::<Vec<u32>>();
+    let (_, last, _) = positions.select_nth_unstable(k - 1);
+    let last = *last;
+
+    Arc::new(
+        first_pos
+            .into_iter()
+            .filter(|(_, pos)| *pos <= last)
+            .map(|(doc_id, _)| (doc_id, docs[&doc_id]))
+            .collect(),
+    )
+}
+
+pub struct TopMatches {
+    pub docs: MatchedDocs,
+    /// Number of matching documents, including the ones left out of `docs`.
+    pub total: usize,
+}
+
 // #[derive(Default, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
 pub struct Trie {
     root: TrieNode,
     fst: Set<Vec<u8>>,
+    id: u32,
+    typo_policy: TypoPolicy,
 }
 
 impl Trie {
@@ -70,9 +222,23 @@ impl Trie {
         Trie {
             root: TrieNode::default(),
             fst: Set::from_iter(words.iter()).unwrap(),
+            id: TRIE_COUNTER.fetch_add(1, AtomicOrdering::Relaxed),
+            typo_policy: TypoPolicy::default(),
         }
     }
 
+    pub fn set_typo_policy(&mut self, typo_policy: TypoPolicy) {
+        self.typo_policy = typo_policy;
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn typo_policy(&self) -> TypoPolicy {
+        self.typo_policy
+    }
+
     pub fn insert(&mut self, word: &str) {
         let mut current_node = &mut self.root;
 
@@ -85,19 +251,27 @@ impl Trie {
 }
 
 impl Trie {
-    pub fn find_words<'a>(&self, query: &'a str, k: usize) -> Vec<(String, usize)> {
+    /// Vocabulary words within `k` edits of `query`, with their edits. The
+    /// automaton for long words with typos grows past what `fst` builds.
+    pub fn find_words<'a>(
+        &self,
+        query: &'a str,
+        k: usize,
+    ) -> Result<Vec<(String, usize)>, MatchError> {
         // let mut pq = PriorityQueue::new();
 
-        let lev = Levenshtein::new(query, k as u32).unwrap();
+        let lev = Levenshtein::new(query, k as u32)
+            .map_err(|_| MatchError::WordTooLong(query.to_owned()))?;
 
         // Apply our fuzzy query to the set we built.
         let stream = self.fst.search(lev).into_stream();
 
         let keys = stream.into_strs().unwrap();
 
-        keys.iter()
+        Ok(keys
+            .iter()
             .map(|key| (key.to_owned(), edit_distance(query, key)))
-            .collect()
+            .collect())
 
         // let mut pq = BTreeSet::new();
 
@@ -178,93 +352,503 @@ impl Trie {
         // similar_words
     }
 
-    fn nr_allowed_errors<'a>(&self, w: &'a str, is_last: bool) -> usize {
+    fn nr_allowed_errors<'a>(&self, w: &'a str, is_last: bool, typos: &TypoPolicy) -> usize {
         // if is_last {
         //     (w.len() as f32).powf(0.8).min(3.0).floor() as usize
         // } else
-        if w.len() > 4 {
-            2
-        } else {
-            1
+        typos.allowed_errors(w)
+    }
+
+    fn similar_words(
+        &self,
+        wi: &str,
+        is_last: bool,
+        typos: &TypoPolicy,
+    ) -> Result<Vec<(String, usize)>, MatchError> {
+        let k = self.nr_allowed_errors(wi, is_last, typos);
+
+        let cache_key = format!("{}::{wi}::{k}", self.id);
+
+        let data = cache::retrieve_from_cache(&cache_key);
+
+        match data {
+            Some(d) => Ok(d),
+            None => {
+                let mut similar_words = self.find_words(wi, k)?;
+                // Closest words first, so a record containing several
+                // variants is always credited with its best match.
+                similar_words.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+
+                cache::insert_into_cache(&cache_key, &similar_words);
+                Ok(similar_words)
+            }
         }
     }
 
-    pub fn find_matches<'a>(
+    /// Like `find_matches`, but only collects enough candidates to fill the
+    /// top `k` results of a single word query ranked by the default rules.
+    ///
+    /// Exactness is the first of those rules, so once the exact matches alone
+    /// fill the top `k` no typo variant can enter it and their postings are
+    /// skipped. Proximity comes next, and for one word it only depends on
+    /// where the word first occurs, so of the exact matches only the ones
+    /// that occur as early as the `k`-th are kept, see `closest_exact`.
+    /// `total` still counts every match. Multi word queries are narrowed by
+    /// intersection and use the full path.
+    ///
+    /// Cancelling the search of `session` stops it between two words.
+    pub fn find_top_matches(
         &self,
-        query: &'a str,
+        query: &str,
         word_map: &WordMap,
-    ) -> HashMap<u32, (usize, usize)> {
+        k: usize,
+        filter: Option<&RoaringBitmap>,
+        typos: &TypoPolicy,
+        session: Option<&SessionMatches>,
+    ) -> Result<TopMatches, MatchError> {
         let query = query.to_lowercase();
 
-        let mut similar_element_lists: Option<HashMap<u32, (usize, usize)>> = None;
+        let words = query.split_whitespace().collect::<Vec<&str>>();
+        if words.len() != 1 {
+            let docs = self.find_matches(&query, word_map, filter, typos, session)?;
+            return Ok(TopMatches {
+                total: docs.len(),
+                docs,
+            });
+        }
 
-        let mut word_pos_mp = HashSet::new();
+        // The full matches of the word, when an earlier search left them.
+        let key = self.matches_key(&words, true, typos);
+        if filter.is_none() {
+            if let Some(matches) = cache::retrieve_matches_from_cache(&key) {
+                return Ok(TopMatches {
+                    total: matches.docs.len(),
+                    docs: closest_exact(&matches.docs, words[0], word_map, k),
+                });
+            }
+        }
 
-        for (i, wi) in query.split_whitespace().enumerate() {
-            let k = self.nr_allowed_errors(wi, i == query.split_whitespace().count() - 1);
+        // Closest words first, so the exact match comes before any typo.
+        let word_vec = self.similar_words(words[0], true, typos)?;
+
+        let mut docs = HashMap::new();
+        let mut exact_docs = 0;
+        // Documents only matched by skipped typo variants, counted but not
+        // ranked.
+        let mut skipped = RoaringBitmap::new();
+        let mut complete = true;
+
+        for (word, edits) in &word_vec {
+            check_cancel(session)?;
+            let word = word_map.get_word(word);
+            // Typo variants don't rank among themselves by their edits, so
+            // either all of them are collected or none.
+            let skip = *edits > 0 && exact_docs >= k;
+            complete &= !skip;
+
+            let val = ((*edits == 0) as usize, *edits);
+            for rec in &word.in_records {
+                if filter.is_some_and(|f| !f.contains(rec.idx)) {
+                    continue;
+                }
+                if !skip {
+                    docs.entry(rec.idx).or_insert(val);
+                } else if !docs.contains_key(&rec.idx) {
+                    skipped.insert(rec.idx);
+                }
+            }
+            if *edits == 0 {
+                exact_docs = docs.len();
+            }
+        }
 
-            let cache_key = format!("{wi}::{k}");
+        let docs = Arc::new(docs);
+        // Nothing skipped, these are the same matches `extend_matches` finds.
+        if complete && filter.is_none() {
+            let matches = PartialMatches {
+                ids: Some(docs.keys().copied().collect()),
+                docs: docs.clone(),
+                used: HashSet::new(),
+            };
+            cache::insert_matches_into_cache(key, Arc::new(matches));
+        }
 
-            let data = cache::retrieve_from_cache(&cache_key);
+        Ok(TopMatches {
+            total: docs.len() + skipped.len() as usize,
+            docs: closest_exact(&docs, words[0], word_map, k),
+        })
+    }
 
-            let word_vec = match data {
-                Some(d) => d,
-                None => {
-                    println!("{cache_key} cache miss");
-                    let similar_words = self.find_words(wi, k);
+    /// Documents matching every query word, as `(exact matches, edits)`.
+    /// Documents outside `filter` are dropped while intersecting, and query
+    /// words match vocabulary words within the edits allowed by `typos`.
+    /// Cancelling the search of `session` stops it between two query words.
+    pub fn find_matches<'a>(
+        &self,
+        query: &'a str,
+        word_map: &WordMap,
+        filter: Option<&RoaringBitmap>,
+        typos: &TypoPolicy,
+        session: Option<&SessionMatches>,
+    ) -> Result<MatchedDocs, MatchError> {
+        let query = query.to_lowercase();
+        let words = query.split_whitespace().collect::<Vec<&str>>();
+        if words.is_empty() {
+            return Ok(Arc::default());
+        }
 
-                    cache::insert_into_cache(&cache_key, &similar_words);
-                    similar_words
-                }
-            };
+        // Intersections of the first words are shared between queries, so
+        // "lord of the r" narrows down what "lord of the" matched. Only the
+        // last word is matched with the filter, and a filtered last step is
+        // not shared.
+        let cacheable = match filter {
+            Some(_) => words.len() - 1,
+            None => words.len(),
+        };
+        let mut start = cacheable;
+        let mut matches = loop {
+            if start == 0 {
+                break Arc::new(PartialMatches::default());
+            }
+            let key = self.matches_key(&words[..start], start == words.len(), typos);
+            let found = session
+                .and_then(|s| s.prefix(&key))
+                .or_else(|| cache::retrieve_matches_from_cache(&key));
+            if let Some(matches) = found {
+                break matches;
+            }
+            start -= 1;
+        };
+
+        let prefix_len = words.len() - 1;
+        let mut prefix = (start == prefix_len && start > 0).then(|| matches.clone());
+        for i in start..words.len() {
+            check_cancel(session)?;
+            let is_last = i == words.len() - 1;
+            let step_filter = if is_last { filter } else { None };
+            matches = Arc::new(self.extend_matches(
+                &matches,
+                words[i],
+                is_last,
+                word_map,
+                step_filter,
+                typos,
+            )?);
+            if i < cacheable {
+                let key = self.matches_key(&words[..=i], is_last, typos);
+                cache::insert_matches_into_cache(key, matches.clone());
+            }
+            if i + 1 == prefix_len {
+                prefix = Some(matches.clone());
+            }
+        }
+
+        if let (Some(session), Some(prefix)) = (session, prefix) {
+            let key = self.matches_key(&words[..prefix_len], false, typos);
+            *session.prefix.lock().unwrap() = Some((key, prefix));
+        }
 
-            let mut curr_records: HashMap<u32, (usize, usize)> = HashMap::new();
-            let _t1 = Instant::now();
-
-            let mut curr_word_doc = HashSet::new();
-
-            for list in &word_vec {
-                let word = word_map.get_word(&list.0);
-                let val = ((list.1 == 0) as usize, list.1);
-                for rec in &word.in_records {
-                    if let None = word_pos_mp.get(rec) {
-                        if let Some(_) = curr_word_doc.get(&rec.idx) {
-                            continue;
-                        }
-                        curr_word_doc.insert(rec.idx.to_owned());
-                        curr_records.insert(rec.idx.clone(), val);
-                        word_pos_mp.insert(rec.clone());
-                    }
+        Ok(matches.docs.clone())
+    }
+
+    fn matches_key(&self, words: &[&str], is_last: bool, typos: &TypoPolicy) -> MatchesKey {
+        MatchesKey {
+            trie: self.id,
+            typos: *typos,
+            words: words.iter().map(|w| w.to_string()).collect(),
+            is_last,
+        }
+    }
+
+    /// Narrows the matches of the words before `wi` down to the documents
+    /// also matching `wi`, one step of `find_matches`.
+    pub fn extend_matches(
+        &self,
+        matches: &PartialMatches,
+        wi: &str,
+        is_last: bool,
+        word_map: &WordMap,
+        filter: Option<&RoaringBitmap>,
+        typos: &TypoPolicy,
+    ) -> Result<PartialMatches, MatchError> {
+        let word_vec = self.similar_words(wi, is_last, typos)?;
+
+        let mut ids = RoaringBitmap::new();
+        let mut docs = HashMap::new();
+        let mut used = HashSet::new();
+
+        for (word, edits) in &word_vec {
+            let word = word_map.get_word(word);
+            for rec in &word.in_records {
+                if filter.is_some_and(|f| !f.contains(rec.idx)) {
+                    continue;
+                }
+                if matches.ids.as_ref().is_some_and(|ids| !ids.contains(rec.idx)) {
+                    continue;
+                }
+                // A posting already credited to an earlier query word can't
+                // match this one too.
+                if ids.contains(rec.idx) || matches.used.contains(&(rec.idx, rec.pos)) {
+                    continue;
+                }
+                let (exact_matches, total_edits) =
+                    matches.docs.get(&rec.idx).copied().unwrap_or_default();
+                ids.insert(rec.idx);
+                docs.insert(
+                    rec.idx,
+                    (exact_matches + (*edits == 0) as usize, total_edits + edits),
+                );
+                if !is_last {
+                    used.insert((rec.idx, rec.pos));
                 }
             }
+        }
 
-            let curr_len = &curr_records.len();
-            let _t2 = Instant::now();
-            println!("find_matches res: {}", (_t2 - _t1).as_millis());
-
-            similar_element_lists = match &similar_element_lists {
-                Some(s) => Some({
-                    let mut tmp_mp = HashMap::new();
-                    for (key, (exact_match, edits)) in s.iter() {
-                        if let Some((is_match, edit)) = curr_records.get(key) {
-                            tmp_mp.insert(*key, (exact_match + is_match, edits + edit));
-                        }
-                    }
-                    tmp_mp
-                }),
-                None => Some(curr_records),
-            };
+        if !is_last {
+            // Postings of the documents dropped here don't matter to later
+            // words.
+            used.extend(
+                matches
+                    .used
+                    .iter()
+                    .filter(|(doc_id, _)| ids.contains(*doc_id)),
+            );
+        }
 
-            println!(
-                "k: {k}, curr_records len = {}, similar_element_lists len = {}",
-                curr_len,
-                similar_element_lists.as_ref().unwrap().len()
+        Ok(PartialMatches {
+            ids: Some(ids),
+            docs: Arc::new(docs),
+            used,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering as AtomicOrdering, Arc};
+
+    use roaring::RoaringBitmap;
+
+    use super::{MatchError, SessionMatches, TopMatches};
+    use crate::storage::cache;
+    use crate::storage::correction::suggest_correction;
+    use crate::storage::document::{Analyzer, Attributes};
+    use crate::storage::feedback::FeedbackStore;
+    use crate::storage::index::{Index, IndexBuilder, IndexSettings};
+    use crate::storage::record::{QueryWords, Record};
+
+    fn index_of(titles: &[(String, u32)]) -> Index {
+        let mut builder = IndexBuilder::new(Analyzer::Standard);
+        for (title, boost) in titles {
+            builder.add_doc(title, *boost, Attributes::default());
+        }
+        let (word_map, doc_map, successors) = builder.build("");
+        Index::new(IndexSettings::default(), word_map, doc_map, successors)
+    }
+
+    fn top_ids(index: &Index, query: &str, k: usize) -> (Vec<u32>, usize) {
+        let typos = index.trie.typo_policy();
+        let matches = index
+            .trie
+            .find_top_matches(query, &index.word_map, k, None, &typos, None)
+            .unwrap();
+        let query = QueryWords::new(query, index.analyzer);
+        let feedback = FeedbackStore::new();
+        let ids = index
+            .doc_map
+            .sort_raw_result(&query, &matches.docs, &feedback, 0, k, Record::rank_cmp)
+            .iter()
+            .map(|r| r.doc_id)
+            .collect();
+        (ids, matches.total)
+    }
+
+    /// The best exact match comes after more than a page worth of others at
+    /// the same position, and typo variants only fill up short pages.
+    #[test]
+    fn early_top_k_matches_exhaustive_ranking() {
+        let mut titles = (0..120)
+            .map(|i| (format!("Matrix Part {i}"), 0))
+            .collect::<Vec<(String, u32)>>();
+        titles.push(("Matrix: Resurrections".to_string(), 500));
+        titles.extend((0..30).map(|i| (format!("Matrx Sequel {i}"), i)));
+        let index = index_of(&titles);
+
+        for k in [1, 10, 100, 121, 122, 200] {
+            let (early, _) = top_ids(&index, "matrix", k);
+            let (exhaustive, total) = top_ids(&index, "matrix", usize::MAX);
+            assert_eq!(early, exhaustive[..k.min(exhaustive.len())], "k = {k}");
+            assert_eq!(total, 151);
+        }
+        assert_eq!(top_ids(&index, "matrix", 10).0[0], 120);
+    }
+
+    /// Exact matches occurring later than the `k`-th earliest one are left
+    /// out of the candidates, the ones tied with it are kept.
+    #[test]
+    fn exact_matches_are_bounded_by_position() {
+        let titles = (0..50)
+            .map(|i| (format!("{}Matrix {i}", "Reloaded ".repeat(i % 5)), i as u32))
+            .collect::<Vec<(String, u32)>>();
+        let index = index_of(&titles);
+        let typos = index.trie.typo_policy();
+
+        // Ten titles at each position, the 15th earliest is at position 1.
+        for _ in 0..2 {
+            let matches = index
+                .trie
+                .find_top_matches("matrix", &index.word_map, 15, None, &typos, None)
+                .unwrap();
+            assert_eq!(matches.docs.len(), 20);
+            assert_eq!(matches.total, 50);
+        }
+        for k in [1, 10, 15, 20, 25, 50] {
+            let (early, _) = top_ids(&index, "matrix", k);
+            let (exhaustive, _) = top_ids(&index, "matrix", usize::MAX);
+            assert_eq!(early, exhaustive[..k], "k = {k}");
+        }
+    }
+
+    /// Documents matched by several skipped typo variants count once, and
+    /// only when they pass the filter.
+    #[test]
+    fn early_total_counts_filtered_documents_once() {
+        let mut titles = (0..20)
+            .map(|i| (format!("Batman {i}"), 0))
+            .collect::<Vec<(String, u32)>>();
+        titles.extend((0..10).map(|i| (format!("Batmn Batmen {i}"), 0)));
+        let index = index_of(&titles);
+        let filter = (0..30).filter(|id| id % 2 == 0).collect::<RoaringBitmap>();
+        let typos = index.trie.typo_policy();
+
+        for k in [5, usize::MAX] {
+            let matches = index
+                .trie
+                .find_top_matches("batman", &index.word_map, k, Some(&filter), &typos, None)
+                .unwrap();
+            assert_eq!(matches.total, 15, "k = {k}");
+        }
+    }
+
+    #[test]
+    fn cancelled_search_stops() {
+        let index = index_of(&[("The Lord of the Rings".to_string(), 0)]);
+        let typos = index.trie.typo_policy();
+        let session = SessionMatches::default();
+        session.cancel.store(true, AtomicOrdering::Relaxed);
+
+        for query in ["lord", "lord of the rings"] {
+            let matches = index.trie.find_top_matches(
+                query,
+                &index.word_map,
+                10,
+                None,
+                &typos,
+                Some(&session),
             );
+            assert!(matches.is_err(), "{query}");
         }
+        session.cancel.store(false, AtomicOrdering::Relaxed);
+        let matches = matches_of(&index, "lord of the rings", Some(&session));
+        assert_eq!(matches.total, 1);
+    }
+
+    const LORD_TITLES: [&str; 7] = [
+        "The Lord of the Rings",
+        "The Lord of the Rings: The Return of the King",
+        "Lord of the Flies",
+        "Lord of War",
+        "Lord of the R",
+        "Lord of the Dance A Musical",
+        "The Rings of the Lord of the Lords",
+    ];
+
+    fn lord_index() -> Index {
+        index_of(&LORD_TITLES.map(|title| (title.to_string(), 0)))
+    }
+
+    fn matches_of(index: &Index, query: &str, session: Option<&SessionMatches>) -> TopMatches {
+        let typos = index.trie.typo_policy();
+        index
+            .trie
+            .find_top_matches(query, &index.word_map, usize::MAX, None, &typos, session)
+            .unwrap()
+    }
 
-        match similar_element_lists {
-            Some(mp) => mp,
-            None => HashMap::new(),
+    /// Searches building on the matches an earlier query left, in the
+    /// shared cache or in a session, find what a cold search finds.
+    #[test]
+    fn reused_prefix_matches_equal_a_cold_search() {
+        let follow_ups = [
+            ("lord of the", "lord of the r"),
+            ("lord of the", "lord of the rings"),
+            ("the lord of the", "the lord of the lords"),
+            ("lord", "lord of"),
+            ("lord of the r", "lord of the"),
+        ];
+        for (first, second) in follow_ups {
+            let cold = matches_of(&lord_index(), second, None);
+            assert!(!cold.docs.is_empty(), "{second}");
+
+            let shared = lord_index();
+            matches_of(&shared, first, None);
+            let warm = matches_of(&shared, second, None);
+            assert_eq!(warm.docs, cold.docs, "{first} -> {second}");
+            assert_eq!(warm.total, cold.total, "{first} -> {second}");
+
+            let session = SessionMatches::default();
+            let index = lord_index();
+            matches_of(&index, first, Some(&session));
+            let warm = matches_of(&index, second, Some(&session));
+            assert_eq!(warm.docs, cold.docs, "{first} -> {second} in a session");
         }
     }
+
+    /// A query ending in a word is never narrowed further as if that word
+    /// had been matched before another.
+    #[test]
+    fn last_word_steps_are_not_reused_as_prefixes() {
+        let index = lord_index();
+        let typos = index.trie.typo_policy();
+        matches_of(&index, "lord of the", None);
+
+        let last = index.trie.matches_key(&["lord", "of", "the"], true, &typos);
+        let prefix = index.trie.matches_key(&["lord", "of", "the"], false, &typos);
+        assert!(cache::retrieve_matches_from_cache(&last).is_some());
+        assert!(cache::retrieve_matches_from_cache(&prefix).is_none());
+        // The words before it are.
+        let prefix = index.trie.matches_key(&["lord", "of"], false, &typos);
+        assert!(cache::retrieve_matches_from_cache(&prefix).is_some());
+    }
+
+    /// Too long for a typo automaton, matched with an error instead of a
+    /// panic.
+    #[test]
+    fn long_words_are_rejected() {
+        let index = lord_index();
+        let typos = index.trie.typo_policy();
+        let word = "supercalifragilisticexpialidocious".repeat(2);
+
+        for query in [word.clone(), format!("lord of {word}"), format!("{word} lord")] {
+            let matches = index
+                .trie
+                .find_top_matches(&query, &index.word_map, 10, None, &typos, None);
+            assert_eq!(matches.err(), Some(MatchError::WordTooLong(word.clone())));
+        }
+        let correction = suggest_correction(&index.trie, &index.word_map, &word);
+        assert_eq!(correction, Err(MatchError::WordTooLong(word)));
+    }
+
+    #[test]
+    fn single_words_are_shared() {
+        let index = lord_index();
+        let typos = index.trie.typo_policy();
+        let cold = matches_of(&index, "lords", None);
+
+        let key = index.trie.matches_key(&["lords"], true, &typos);
+        let cached = cache::retrieve_matches_from_cache(&key).unwrap();
+        assert!(Arc::ptr_eq(&cached.docs, &cold.docs));
+        let warm = matches_of(&index, "lords", None);
+        assert!(Arc::ptr_eq(&warm.docs, &cold.docs));
+    }
 }
@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 
 static WORD_COUNTER: AtomicU32 = AtomicU32::new(0);
 
-#[derive(Hash, PartialEq, Eq, Serialize, Deserialize, Message)]
+#[derive(Clone, Hash, PartialEq, Eq, Serialize, Deserialize, Message)]
 pub struct WordInRecord {
     #[prost(uint32, tag = "1")]
     pub idx: u32,    
@@ -14,7 +14,7 @@ pub struct WordInRecord {
     pub pos: u32,
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize, Message)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Message)]
 pub struct Word {
     #[prost(uint32, tag = "1")]
     id: u32,
@@ -32,7 +32,7 @@ impl Word {
 }
 
 
-#[derive(Serialize, Deserialize, Message)]
+#[derive(Clone, Serialize, Deserialize, Message)]
 pub struct WordMap {
     #[prost(map = "string, message", tag = "1")]
     pub word_hash: HashMap<String, Word>
@@ -55,6 +55,17 @@ impl WordMap {
         word
     }
 
+    /// Drops the postings of a document, and the words left without any.
+    pub fn remove_doc(&mut self, doc_id: u32) {
+        self.word_hash.retain(|_, word| {
+            let before = word.in_records.len();
+            word.in_records.retain(|rec| rec.idx != doc_id);
+            word.popularity -= (before - word.in_records.len()) as u32;
+            word.position = word.in_records.iter().map(|rec| rec.pos).collect();
+            !word.in_records.is_empty()
+        });
+    }
+
     pub fn get_word(&self, word: &str) -> &Word {
 
         let word = self.word_hash.get(word).unwrap();